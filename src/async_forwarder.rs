@@ -0,0 +1,232 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use futures_util::future::join_all;
+use tokio::net::UdpSocket;
+use tokio::time::timeout_at;
+
+use crate::dns_message::DnsMessage;
+use crate::dns_question_and_answer::DnsQuestion;
+use crate::edns::SERVER_UDP_PAYLOAD;
+use crate::error::DnsError;
+use crate::forwarder::{
+    build_single_question_query, local_addr_for, validate_response, ForwarderConfig,
+    UpstreamResponse,
+};
+use crate::logging::warning;
+use crate::upstream::UpstreamPool;
+
+/// Send a query once and wait until `attempt_deadline` for the matching reply
+/// Async version of the blocking forwarder's attempt; invalid datagrams are dropped
+/// Returns None when nothing valid arrived in time
+async fn attempt(
+    socket: &UdpSocket,
+    resolver: SocketAddr,
+    query: &[u8],
+    query_id: u16,
+    question: &DnsQuestion,
+    attempt_deadline: Instant,
+) -> Result<Option<DnsMessage>, DnsError> {
+    socket
+        .send_to(query, resolver)
+        .await
+        .map_err(DnsError::io("Failed to send to resolver"))?;
+
+    let mut response_buf = [0u8; SERVER_UDP_PAYLOAD as usize];
+
+    loop {
+        let received = timeout_at(attempt_deadline.into(), socket.recv_from(&mut response_buf));
+        let Ok(received) = received.await else {
+            return Ok(None);
+        };
+
+        let (response_size, source) =
+            received.map_err(DnsError::io("Failed to receive from resolver"))?;
+
+        match validate_response(
+            &response_buf[..response_size],
+            source,
+            resolver,
+            query_id,
+            question,
+        ) {
+            Ok(message) => return Ok(Some(message)),
+            Err(reason) => warning!("Ignoring upstream datagram: {}", reason),
+        }
+    }
+}
+
+/// Ask the upstreams for one question until one of them answers
+/// Same rounds, sockets and health bookkeeping as the blocking forwarder
+async fn exchange(
+    upstreams: &UpstreamPool,
+    question: &DnsQuestion,
+    checking_disabled: bool,
+    config: &ForwarderConfig,
+    deadline: Instant,
+) -> Result<DnsMessage, DnsError> {
+    // Fresh random ID, so replies cannot be predicted from the client's query
+    let query_id: u16 = rand::random();
+    let query = build_single_question_query(query_id, question, checking_disabled)?;
+
+    let candidates = upstreams.candidates();
+    let mut sockets: Vec<Option<UdpSocket>> = candidates.iter().map(|_| None).collect();
+    let total_attempts = (config.retries + 1) * candidates.len() as u32;
+    let mut attempts = 0;
+
+    'rounds: for _ in 0..=config.retries {
+        for (upstream, socket) in candidates.iter().zip(sockets.iter_mut()) {
+            let now = Instant::now();
+            if now >= deadline {
+                break 'rounds;
+            }
+            attempts += 1;
+
+            let socket = match socket {
+                Some(socket) => socket,
+                None => socket.insert(
+                    UdpSocket::bind(local_addr_for(upstream.addr))
+                        .await
+                        .map_err(DnsError::io("Failed to bind upstream socket"))?,
+                ),
+            };
+            let attempt_deadline = deadline.min(now + config.attempt_timeout);
+
+            match attempt(
+                socket,
+                upstream.addr,
+                &query,
+                query_id,
+                question,
+                attempt_deadline,
+            )
+            .await
+            {
+                Ok(Some(message)) => {
+                    upstream.record_success(now.elapsed());
+                    return Ok(message);
+                }
+                Ok(None) => warning!(
+                    "No answer from resolver {} (attempt {}/{})",
+                    upstream.addr,
+                    attempts,
+                    total_attempts
+                ),
+                Err(e) => warning!(
+                    "Resolver {} failed (attempt {}/{}): {}",
+                    upstream.addr,
+                    attempts,
+                    total_attempts,
+                    e
+                ),
+            }
+            upstream.record_failure();
+        }
+    }
+
+    Err(DnsError::UpstreamTimeout {
+        resolver: upstreams.to_string(),
+        attempts,
+    })
+}
+
+/// Async version of `forward_to_resolver`: one result per question, all questions
+/// in flight at once on the current task
+/// Dropping the returned future cancels every outstanding upstream query
+pub async fn forward_to_resolver_async(
+    upstreams: &UpstreamPool,
+    questions: &[DnsQuestion],
+    checking_disabled: bool,
+    config: &ForwarderConfig,
+) -> Vec<Result<UpstreamResponse, DnsError>> {
+    let deadline = Instant::now() + config.deadline;
+
+    join_all(questions.iter().map(|question| async move {
+        exchange(upstreams, question, checking_disabled, config, deadline)
+            .await
+            .and_then(|message| UpstreamResponse::for_question(question, message))
+    }))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::Strategy;
+    use std::thread;
+    use std::time::Duration;
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion {
+            name: name.to_string(),
+            qtype: 1,
+            qclass: 1,
+        }
+    }
+
+    fn quick_config() -> ForwarderConfig {
+        ForwarderConfig {
+            attempt_timeout: Duration::from_millis(50),
+            retries: 2,
+            deadline: Duration::from_secs(1),
+        }
+    }
+
+    /// Blocking upstream stub that ignores the first `ignore` queries, then echoes
+    /// every further one back as a response
+    fn spawn_upstream(ignore: usize) -> UpstreamPool {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let mut received = 0;
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                received += 1;
+                if received > ignore {
+                    buf[2] |= 0x80; // QR: turn the query into a response
+                    socket.send_to(&buf[..size], source).unwrap();
+                }
+            }
+        });
+
+        UpstreamPool::new(&[addr], Strategy::Failover).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forward_async_retries_after_lost_packet() {
+        let upstreams = spawn_upstream(1);
+        let results =
+            forward_to_resolver_async(&upstreams, &[question("a.example")], false, &quick_config())
+                .await;
+        assert!(results[0].is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_forward_async_answers_each_question() {
+        let upstreams = spawn_upstream(0);
+        let questions = [question("a.example"), question("b.example")];
+
+        let results = forward_to_resolver_async(&upstreams, &questions, false, &quick_config())
+            .await
+            .into_iter()
+            .map(|result| result.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_forward_async_gives_up_after_retries() {
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+        let upstreams = UpstreamPool::new(&[addr], Strategy::Failover).unwrap();
+
+        let results =
+            forward_to_resolver_async(&upstreams, &[question("a.example")], false, &quick_config())
+                .await;
+        assert!(matches!(
+            results[0],
+            Err(DnsError::UpstreamTimeout { attempts: 3, .. })
+        ));
+    }
+}
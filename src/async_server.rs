@@ -0,0 +1,236 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::edns::SERVER_UDP_PAYLOAD;
+use crate::error::DnsError;
+use crate::listener::bind_pair;
+use crate::logging::{debug, error, warning};
+use crate::resolver::SharedResolver;
+use crate::server::{triage_request, Transport, Triage, TCP_IDLE_TIMEOUT};
+use crate::tcp;
+
+/// How long in-flight requests may run on after shutdown before they are cancelled
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// Async counterpart of `DnsServer`, for embedding in a tokio runtime
+/// Every request is its own task, so a slow upstream only holds up its own client
+pub struct AsyncDnsServer {
+    udp_socket: Arc<UdpSocket>,
+    tcp_listener: TcpListener,
+    resolver: Arc<SharedResolver>,
+}
+
+impl AsyncDnsServer {
+    /// Create a new DNS server bound to the given address (both UDP and TCP)
+    /// answering from whatever `resolver` currently holds
+    /// With port 0, TCP listens on the same port the system picked for UDP;
+    /// for several addresses, run one server per address sharing the resolver
+    pub async fn bind(
+        bind_addr: SocketAddr,
+        resolver: Arc<SharedResolver>,
+    ) -> Result<Self, String> {
+        let (udp_socket, tcp_listener) = bind_pair(bind_addr)?;
+        let register = |e| format!("Failed to register {} with the runtime: {}", bind_addr, e);
+
+        udp_socket.set_nonblocking(true).map_err(register)?;
+        tcp_listener.set_nonblocking(true).map_err(register)?;
+        let udp_socket = UdpSocket::from_std(udp_socket).map_err(register)?;
+        let tcp_listener = TcpListener::from_std(tcp_listener).map_err(register)?;
+
+        Ok(Self {
+            udp_socket: Arc::new(udp_socket),
+            tcp_listener,
+            resolver,
+        })
+    }
+
+    /// Address the UDP socket is bound to (useful when binding to port 0)
+    #[allow(dead_code)] // Used by embedders and tests
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.udp_socket.local_addr()
+    }
+
+    /// Serve requests until `shutdown` completes
+    /// Then no new requests are accepted; those in flight get `SHUTDOWN_GRACE`
+    /// to finish before they are cancelled
+    pub async fn run(self, shutdown: impl Future<Output = ()>) {
+        let mut tasks = JoinSet::new();
+        let mut buf = [0u8; SERVER_UDP_PAYLOAD as usize];
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                received = self.udp_socket.recv_from(&mut buf) => match received {
+                    Ok((size, source)) => {
+                        debug!("Received {} bytes from {}", size, source);
+                        tasks.spawn(handle_udp(
+                            buf[..size].to_vec(),
+                            source,
+                            self.udp_socket.clone(),
+                            self.resolver.clone(),
+                        ));
+                    }
+                    Err(e) => {
+                        error!("Error receiving data: {}", e);
+                        break;
+                    }
+                },
+                accepted = self.tcp_listener.accept() => match accepted {
+                    Ok((stream, peer)) => {
+                        tasks.spawn(handle_tcp_connection(stream, peer, self.resolver.clone()));
+                    }
+                    Err(e) => error!("Error accepting TCP connection: {}", e),
+                },
+                // Reap finished requests so the set does not grow without bound
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+            }
+        }
+
+        let drain = async { while tasks.join_next().await.is_some() {} };
+        if timeout(SHUTDOWN_GRACE, drain).await.is_err() {
+            warning!("Cancelling {} requests still in flight", tasks.len());
+            tasks.shutdown().await;
+        }
+    }
+}
+
+/// Parse, resolve and encode one request
+async fn handle_request(
+    buf: &[u8],
+    transport: Transport,
+    resolver: &SharedResolver,
+) -> Result<Vec<u8>, DnsError> {
+    match triage_request(buf, transport)? {
+        Triage::Respond(response) => Ok(response),
+        Triage::Resolve(accepted) => {
+            let resolver = resolver.current();
+            let resolved = resolver.resolve_async(&accepted.request).await;
+            accepted.respond(resolved)
+        }
+    }
+}
+
+/// Answer one UDP datagram
+async fn handle_udp(
+    request: Vec<u8>,
+    source: SocketAddr,
+    socket: Arc<UdpSocket>,
+    resolver: Arc<SharedResolver>,
+) {
+    match handle_request(&request, Transport::Udp, &resolver).await {
+        Ok(response) => {
+            if let Err(e) = socket.send_to(&response, source).await {
+                error!("Error sending response to {}: {}", source, e);
+            }
+        }
+        Err(e) => error!("Error handling request: {}", e),
+    }
+}
+
+/// Serve length-prefixed queries from a TCP connection until the client closes it
+/// or stays idle for `TCP_IDLE_TIMEOUT`
+async fn handle_tcp_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    resolver: Arc<SharedResolver>,
+) {
+    loop {
+        let request = match timeout(TCP_IDLE_TIMEOUT, tcp::read_message_async(&mut stream)).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) | Err(_) => break,
+            Ok(Err(e)) => {
+                error!("Error reading TCP request from {}: {}", peer, e);
+                break;
+            }
+        };
+
+        debug!("Received {} bytes over TCP from {}", request.len(), peer);
+
+        match handle_request(&request, Transport::Tcp, &resolver).await {
+            Ok(response) => {
+                if let Err(e) = tcp::write_message_async(&mut stream, &response).await {
+                    error!("Error sending TCP response to {}: {}", peer, e);
+                    break;
+                }
+            }
+            Err(e) => error!("Error handling request: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_header::DnsHeader;
+    use crate::dns_message::DnsMessage;
+    use crate::dns_question_and_answer::DnsQuestion;
+    use crate::forwarder::ForwarderConfig;
+    use crate::local::LocalRecords;
+    use crate::resolver::Resolver;
+    use tokio::sync::oneshot;
+
+    fn query() -> Vec<u8> {
+        let mut query = DnsMessage::new(DnsHeader {
+            id: 0x1234,
+            flags: 0x0100,
+            question_count: 0,
+            answer_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        });
+        query.questions.push(DnsQuestion {
+            name: "example.com".to_string(),
+            qtype: 1,
+            qclass: 1,
+        });
+        query.to_bytes().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serves_udp_and_tcp_until_shutdown() {
+        let resolver = Arc::new(SharedResolver::new(Resolver::new(
+            LocalRecords::new(),
+            None,
+            ForwarderConfig::default(),
+            None,
+        )));
+        let server = AsyncDnsServer::bind("127.0.0.1:0".parse().unwrap(), resolver)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(server.run(async {
+            let _ = stopped.await;
+        }));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&query(), addr).await.unwrap();
+        let mut buf = [0u8; 512];
+        let (size, _) = client.recv_from(&mut buf).await.unwrap();
+        let response = DnsMessage::from_bytes(&buf[..size]).unwrap();
+        assert_eq!(response.header.id, 0x1234);
+        assert_eq!(response.answers.len(), 1);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        tcp::write_message_async(&mut stream, &query())
+            .await
+            .unwrap();
+        let response = tcp::read_message_async(&mut stream).await.unwrap().unwrap();
+        assert_eq!(DnsMessage::from_bytes(&response).unwrap().header.id, 0x1234);
+        drop(stream);
+
+        stop.send(()).unwrap();
+        timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
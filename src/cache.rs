@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::dns_message::RCODE_NXDOMAIN;
+use crate::dns_question_and_answer::{DnsAnswer, DnsQuestion, RecordType};
+use crate::forwarder::UpstreamResponse;
+use crate::rdata::RData;
+
+/// Upper bound on how long a negative answer is cached (RFC 2308 section 5)
+const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+
+/// Questions are cached case-insensitively (RFC 4343)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl CacheKey {
+    fn new(question: &DnsQuestion) -> Self {
+        CacheKey {
+            name: question.name.to_ascii_lowercase(),
+            qtype: question.qtype,
+            qclass: question.qclass,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: UpstreamResponse, // Records with the TTLs they had when stored
+    stored_at: Instant,
+    expires_at: Instant, // Stored time plus the lowest TTL in the response
+    last_used: u64,      // Key into `CacheInner::recency`
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    recency: BTreeMap<u64, CacheKey>, // Least recently used first
+    clock: u64,                       // Bumped on every use
+}
+
+impl CacheInner {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+
+    /// Drop the least recently used entry
+    fn evict_one(&mut self) {
+        if let Some((_, key)) = self.recency.pop_first() {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// In-memory cache of upstream responses, one entry per question
+/// Entries expire with their lowest TTL; when full, the least recently used goes
+/// NXDOMAIN and NODATA responses are cached too, for as long as their SOA allows
+#[derive(Debug)]
+pub struct Cache {
+    max_entries: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize) -> Self {
+        Cache {
+            max_entries,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// The cache only holds copies, so a panic elsewhere cannot leave it inconsistent
+    fn inner(&self) -> MutexGuard<'_, CacheInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Look up a cached response, with TTLs reduced by the time it has been stored
+    pub fn get(&self, question: &DnsQuestion) -> Option<UpstreamResponse> {
+        self.get_at(question, Instant::now())
+    }
+
+    fn get_at(&self, question: &DnsQuestion, now: Instant) -> Option<UpstreamResponse> {
+        let key = CacheKey::new(question);
+        let mut inner = self.inner();
+
+        let expired = now >= inner.entries.get(&key)?.expires_at;
+        if expired {
+            inner.remove(&key);
+            return None;
+        }
+
+        let tick = inner.tick();
+        let entry = inner.entries.get_mut(&key)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        let age = now.duration_since(entry.stored_at).as_secs() as u32;
+        let response = entry.response.aged(age);
+
+        inner.recency.remove(&previous);
+        inner.recency.insert(tick, key);
+
+        Some(response)
+    }
+
+    /// Store the response to a question
+    /// Failures, negative responses without a SOA and zero TTLs are not cached
+    pub fn insert(&self, question: &DnsQuestion, response: &UpstreamResponse) {
+        self.insert_at(question, response, Instant::now());
+    }
+
+    fn insert_at(&self, question: &DnsQuestion, response: &UpstreamResponse, now: Instant) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let Some(response) = cacheable(response) else {
+            return;
+        };
+
+        let ttl = response
+            .records()
+            .map(|record| record.ttl)
+            .min()
+            .unwrap_or(0);
+        if ttl == 0 {
+            return;
+        }
+
+        let key = CacheKey::new(question);
+        let mut inner = self.inner();
+
+        inner.remove(&key);
+        while inner.entries.len() >= self.max_entries {
+            inner.evict_one();
+        }
+
+        let tick = inner.tick();
+        inner.recency.insert(tick, key.clone());
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                response,
+                stored_at: now,
+                expires_at: now + Duration::from_secs(ttl as u64),
+                last_used: tick,
+            },
+        );
+    }
+}
+
+/// Copy of a response in the form it is cached, or None if it must not be
+/// A negative response (NXDOMAIN, or NODATA: no error but no answers) is cached
+/// by its SOA, whose TTL becomes the lower of its own TTL and the SOA minimum
+fn cacheable(response: &UpstreamResponse) -> Option<UpstreamResponse> {
+    let negative = match response.rcode {
+        0 => response.answers.is_empty(),
+        RCODE_NXDOMAIN => true,
+        _ => return None,
+    };
+
+    let mut response = response.clone();
+    if !negative {
+        return Some(response);
+    }
+
+    let soa = response
+        .authorities
+        .iter_mut()
+        .find(|record| record.rtype == RecordType::SOA.to_u16())?;
+    let RData::SOA { minimum, .. } = soa.data().ok()? else {
+        return None;
+    };
+    soa.ttl = soa.ttl.min(minimum).min(MAX_NEGATIVE_TTL);
+
+    Some(response)
+}
+
+impl UpstreamResponse {
+    /// Every record in the response, section by section
+    fn records(&self) -> impl Iterator<Item = &DnsAnswer> {
+        self.answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+    }
+
+    /// Copy of the response with `age` seconds taken off every TTL
+    fn aged(&self, age: u32) -> Self {
+        let mut response = self.clone();
+        for section in [
+            &mut response.answers,
+            &mut response.authorities,
+            &mut response.additionals,
+        ] {
+            for record in section.iter_mut() {
+                record.ttl = record.ttl.saturating_sub(age);
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion {
+            name: name.to_string(),
+            qtype: 1,
+            qclass: 1,
+        }
+    }
+
+    fn response(ttl: u32) -> UpstreamResponse {
+        UpstreamResponse {
+            answers: vec![DnsAnswer::new_a_record(
+                "example.com".to_string(),
+                ttl,
+                [192, 0, 2, 1],
+            )],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hit_decrements_ttl() {
+        let cache = Cache::new(10);
+        let now = Instant::now();
+        cache.insert_at(&question("example.com"), &response(300), now);
+
+        let hit = cache
+            .get_at(&question("EXAMPLE.com"), now + Duration::from_secs(100))
+            .unwrap();
+        assert_eq!(hit.answers[0].ttl, 200);
+    }
+
+    #[test]
+    fn test_entry_expires_with_ttl() {
+        let cache = Cache::new(10);
+        let now = Instant::now();
+        cache.insert_at(&question("example.com"), &response(60), now);
+
+        let expiry = now + Duration::from_secs(60);
+        assert!(cache.get_at(&question("example.com"), expiry).is_none());
+        assert!(cache.inner().entries.is_empty());
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> DnsAnswer {
+        let rdata = RData::SOA {
+            mname: "ns.example.com".to_string(),
+            rname: "admin.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 900,
+            expire: 1209600,
+            minimum,
+        };
+        DnsAnswer::new(
+            "example.com".to_string(),
+            RecordType::SOA.to_u16(),
+            1,
+            ttl,
+            rdata.to_bytes().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_nxdomain_cached_by_soa_minimum() {
+        let cache = Cache::new(10);
+        let now = Instant::now();
+        let nxdomain = UpstreamResponse {
+            rcode: RCODE_NXDOMAIN,
+            authorities: vec![soa(3600, 300)],
+            ..Default::default()
+        };
+        cache.insert_at(&question("missing.example.com"), &nxdomain, now);
+
+        let hit = cache
+            .get_at(
+                &question("missing.example.com"),
+                now + Duration::from_secs(10),
+            )
+            .unwrap();
+        assert_eq!(hit.rcode, RCODE_NXDOMAIN);
+        assert_eq!(hit.authorities[0].ttl, 290);
+
+        let expiry = now + Duration::from_secs(300);
+        assert!(cache
+            .get_at(&question("missing.example.com"), expiry)
+            .is_none());
+    }
+
+    #[test]
+    fn test_nodata_cached_by_soa_ttl() {
+        let cache = Cache::new(10);
+        let nodata = UpstreamResponse {
+            authorities: vec![soa(60, 300)],
+            ..Default::default()
+        };
+        cache.insert(&question("example.com"), &nodata);
+
+        let hit = cache.get(&question("example.com")).unwrap();
+        assert_eq!(hit.rcode, 0);
+        assert!(hit.answers.is_empty());
+        assert!(hit.authorities[0].ttl <= 60);
+    }
+
+    #[test]
+    fn test_not_cached() {
+        let cache = Cache::new(10);
+        cache.insert(&question("zero.example"), &response(0));
+        cache.insert(&question("empty.example"), &UpstreamResponse::default());
+        assert!(cache.get(&question("zero.example")).is_none());
+        assert!(cache.get(&question("empty.example")).is_none());
+
+        let servfail = UpstreamResponse {
+            rcode: crate::dns_message::RCODE_SERVFAIL,
+            ..response(300)
+        };
+        cache.insert(&question("servfail.example"), &servfail);
+        assert!(cache.get(&question("servfail.example")).is_none());
+
+        let disabled = Cache::new(0);
+        disabled.insert(&question("example.com"), &response(300));
+        assert!(disabled.get(&question("example.com")).is_none());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = Cache::new(2);
+        cache.insert(&question("a.example"), &response(300));
+        cache.insert(&question("b.example"), &response(300));
+
+        // Touch a, so b is the least recently used
+        assert!(cache.get(&question("a.example")).is_some());
+        cache.insert(&question("c.example"), &response(300));
+
+        assert!(cache.get(&question("a.example")).is_some());
+        assert!(cache.get(&question("b.example")).is_none());
+        assert!(cache.get(&question("c.example")).is_some());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::dns_question_and_answer::{DnsAnswer, RecordType};
+use crate::rdata::RData;
+
+/// Most CNAME records followed for one answer; longer chains are treated like loops
+pub const MAX_CHAIN_LENGTH: usize = 8;
+
+/// The records of an answer that lie on the chain from the query name
+#[derive(Debug)]
+pub struct Chain {
+    pub records: Vec<DnsAnswer>, // CNAMEs in chain order, then the records of the type asked
+    pub tail: Option<String>,    // The name the chain ends at, when it has no records there
+}
+
+impl Chain {
+    /// Whether the chain ends at a name other than the query name without an answer there
+    pub fn is_incomplete(&self, name: &str) -> bool {
+        self.tail
+            .as_deref()
+            .is_some_and(|tail| !tail.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Follow the CNAME records in `answers` from `name` to the records of `qtype`
+/// (RFC 1034 section 3.6.2); records off the chain are left out
+/// Fails on a loop, or a chain longer than `MAX_CHAIN_LENGTH`
+pub fn follow(name: &str, qtype: u16, answers: &[DnsAnswer]) -> Result<Chain, String> {
+    let cname = RecordType::CNAME.to_u16();
+    let mut records = Vec::new();
+    let mut current = name.to_string();
+    let mut seen = vec![name.to_ascii_lowercase()];
+
+    loop {
+        let owned: Vec<&DnsAnswer> = answers
+            .iter()
+            .filter(|record| record.name.eq_ignore_ascii_case(&current))
+            .collect();
+
+        let matching = owned.iter().filter(|record| record.rtype == qtype);
+        if qtype == cname || matching.clone().next().is_some() {
+            records.extend(matching.map(|&record| record.clone()));
+            return Ok(Chain {
+                records,
+                tail: None,
+            });
+        }
+
+        let Some(alias) = owned.iter().find(|record| record.rtype == cname) else {
+            return Ok(Chain {
+                records,
+                tail: Some(current),
+            });
+        };
+        let target = match alias.data() {
+            Ok(RData::CNAME(target)) => target,
+            _ => return Err(format!("Invalid CNAME record for {}", current)),
+        };
+        records.push((*alias).clone());
+
+        let key = target.to_ascii_lowercase();
+        if seen.contains(&key) {
+            return Err(format!("CNAME loop at {}", target));
+        }
+        if seen.len() > MAX_CHAIN_LENGTH {
+            return Err(format!(
+                "CNAME chain from {} is longer than {} records",
+                name, MAX_CHAIN_LENGTH
+            ));
+        }
+        seen.push(key);
+        current = target;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_question_and_answer::encode_domain_name;
+
+    fn alias(name: &str, target: &str) -> DnsAnswer {
+        DnsAnswer::new(
+            name.to_string(),
+            RecordType::CNAME.to_u16(),
+            1,
+            300,
+            encode_domain_name(target).unwrap(),
+        )
+    }
+
+    fn address(name: &str) -> DnsAnswer {
+        DnsAnswer::new_a_record(name.to_string(), 300, [192, 0, 2, 1])
+    }
+
+    #[test]
+    fn test_follow_keeps_only_the_chain() {
+        let answers = vec![
+            address("c.example"),
+            alias("a.example", "B.example"),
+            address("elsewhere.example"), // Not on the chain
+            alias("b.example", "c.example"),
+        ];
+
+        let chain = follow("A.example", 1, &answers).unwrap();
+        let names: Vec<&str> = chain.records.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["a.example", "b.example", "c.example"]);
+        assert!(!chain.is_incomplete("A.example"));
+
+        let partial = follow("a.example", 1, &answers[1..2]).unwrap();
+        assert_eq!(partial.tail.as_deref(), Some("B.example"));
+        assert!(partial.is_incomplete("a.example"));
+
+        let asked_for_alias = follow("a.example", 5, &answers).unwrap();
+        assert_eq!(asked_for_alias.records.len(), 1);
+    }
+
+    #[test]
+    fn test_follow_rejects_loops_and_long_chains() {
+        let looped = vec![
+            alias("a.example", "b.example"),
+            alias("b.example", "a.example"),
+        ];
+        assert!(follow("a.example", 1, &looped).is_err());
+
+        let long: Vec<DnsAnswer> = (0..=MAX_CHAIN_LENGTH)
+            .map(|i| alias(&format!("{}.example", i), &format!("{}.example", i + 1)))
+            .collect();
+        assert!(follow("0.example", 1, &long).is_err());
+        assert!(follow("1.example", 1, &long).is_ok());
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::cache::Cache;
+use crate::dns_question_and_answer::{DnsAnswer, RecordClass, RecordType};
+use crate::error::ConfigError;
+use crate::forwarder::ForwarderConfig;
+use crate::hosts_file::load_hosts_file;
+use crate::local::{LocalRecords, Zone};
+use crate::logging::LogLevel;
+use crate::rdata::{qualify_name, split_fields, RData};
+use crate::resolver::Resolver;
+use crate::server::WorkerConfig;
+use crate::upstream::{Strategy, UpstreamPool};
+use crate::zone_file::load_zone_file;
+
+/// TTL of local records that do not set one
+const DEFAULT_RECORD_TTL: u32 = 300;
+
+/// Everything the server can be configured with, as read from a TOML file
+/// Every section and key is optional; what is left out keeps its default
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>, // Addresses served over UDP and TCP
+    pub server: ServerConfig,
+    pub upstream: UpstreamConfig,
+    pub cache: CacheConfig,
+    pub logging: LoggingConfig,
+    pub records: Vec<RecordConfig>, // Local records, answered before any upstream
+    pub zones: Vec<ZoneConfig>,     // Zones answered authoritatively, before everything else
+    pub hosts_files: Vec<PathBuf>,  // Hosts-format files, read into the local records
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 2053))],
+            server: ServerConfig::default(),
+            upstream: UpstreamConfig::default(),
+            cache: CacheConfig::default(),
+            logging: LoggingConfig::default(),
+            records: Vec::new(),
+            zones: Vec::new(),
+            hosts_files: Vec::new(),
+        }
+    }
+}
+
+/// `[server]`: how requests are served
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(rename = "async")]
+    pub use_async: bool, // Tokio-based server instead of the worker pool
+    pub workers: usize,
+    pub queue_size: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let workers = WorkerConfig::default();
+        ServerConfig {
+            use_async: false,
+            workers: workers.workers,
+            queue_size: workers.queue_size,
+        }
+    }
+}
+
+/// `[upstream]`: the resolvers queries are forwarded to
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub resolvers: Vec<String>, // None: answer everything locally
+    pub strategy: Strategy,
+    pub timeout_ms: u64,
+    pub retries: u32,
+    pub deadline_ms: u64,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        let forwarder = ForwarderConfig::default();
+        UpstreamConfig {
+            resolvers: Vec::new(),
+            strategy: Strategy::default(),
+            timeout_ms: forwarder.attempt_timeout.as_millis() as u64,
+            retries: forwarder.retries,
+            deadline_ms: forwarder.deadline.as_millis() as u64,
+        }
+    }
+}
+
+/// `[cache]`: the cache of upstream answers
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub size: usize, // Maximum number of entries, 0 disables the cache
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { size: 1024 }
+    }
+}
+
+/// `[logging]`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+}
+
+/// `[[records]]`: one local record, with its data in zone file format
+/// (e.g. `type = "MX"`, `value = "10 mail.example.com"`)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub rtype: String,
+    #[serde(default = "default_record_ttl")]
+    pub ttl: u32,
+    pub value: String,
+}
+
+fn default_record_ttl() -> u32 {
+    DEFAULT_RECORD_TTL
+}
+
+/// `[[zones]]`: a zone file to serve, e.g. `origin = "example.com"`, `file = "example.zone"`
+/// A relative `file` is found next to the configuration file
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    pub origin: String,
+    pub file: PathBuf,
+}
+
+impl Config {
+    /// Read a configuration file
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+        let mut config: Config = toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.display().to_string(),
+            source,
+        })?;
+
+        if let Some(directory) = path.parent() {
+            for zone in &mut config.zones {
+                zone.file = directory.join(&zone.file);
+            }
+            for hosts_file in &mut config.hosts_files {
+                *hosts_file = directory.join(&*hosts_file);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Check the settings serde cannot, like ranges and record data
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+
+        if self.listen.is_empty() {
+            return invalid("No address to listen on");
+        }
+        if self.server.workers == 0 {
+            return invalid("server.workers must be at least 1");
+        }
+        if self.server.queue_size == 0 {
+            return invalid("server.queue_size must be at least 1");
+        }
+        if self.upstream.timeout_ms == 0 {
+            return invalid("upstream.timeout_ms must be at least 1");
+        }
+        if self.upstream.deadline_ms == 0 {
+            return invalid("upstream.deadline_ms must be at least 1");
+        }
+
+        self.local_records().map(|_| ())
+    }
+
+    pub fn worker_config(&self) -> WorkerConfig {
+        WorkerConfig {
+            workers: self.server.workers,
+            queue_size: self.server.queue_size,
+        }
+    }
+
+    pub fn forwarder_config(&self) -> ForwarderConfig {
+        ForwarderConfig {
+            attempt_timeout: Duration::from_millis(self.upstream.timeout_ms),
+            retries: self.upstream.retries,
+            deadline: Duration::from_millis(self.upstream.deadline_ms),
+        }
+    }
+
+    /// Turn the `[[records]]` entries and the hosts files into records ready to answer
+    /// with, and load the `[[zones]]`, rejecting any that fail validation
+    pub fn local_records(&self) -> Result<LocalRecords, ConfigError> {
+        let mut local = LocalRecords::new();
+
+        for record in &self.records {
+            let answer = record.to_answer().map_err(|e| {
+                ConfigError::Invalid(format!(
+                    "Record {} {} \"{}\": {}",
+                    record.name, record.rtype, record.value, e
+                ))
+            })?;
+            local.add(answer);
+        }
+
+        for path in &self.hosts_files {
+            for answer in load_hosts_file(path)? {
+                local.add(answer);
+            }
+        }
+
+        for zone in &self.zones {
+            let invalid = |e: String| {
+                ConfigError::Invalid(format!(
+                    "Zone {} ({}): {}",
+                    zone.origin,
+                    zone.file.display(),
+                    e
+                ))
+            };
+            let origin = qualify_name(&zone.origin, ".").map_err(|e| invalid(e.to_string()))?;
+            let records = load_zone_file(&zone.file, &origin)?;
+            local
+                .add_zone(Zone::new(&origin, records).map_err(invalid)?)
+                .map_err(invalid)?;
+        }
+
+        Ok(local)
+    }
+
+    /// Build everything that answers queries
+    /// Upstream names are resolved here, so this also catches unknown hosts
+    pub fn resolver(&self) -> Result<Resolver, ConfigError> {
+        self.validate()?;
+
+        let upstreams = if self.upstream.resolvers.is_empty() {
+            None
+        } else {
+            let pool = UpstreamPool::new(&self.upstream.resolvers, self.upstream.strategy)
+                .map_err(|e| ConfigError::Invalid(format!("upstream.resolvers: {}", e)))?;
+            Some(pool)
+        };
+        let cache = (self.cache.size > 0).then(|| Cache::new(self.cache.size));
+
+        Ok(Resolver::new(
+            self.local_records()?,
+            upstreams,
+            self.forwarder_config(),
+            cache,
+        ))
+    }
+
+    /// What differs in `new`, one line per setting, for the reload log
+    /// Listeners and the server section are only read at startup, which is pointed out
+    pub fn changes(&self, new: &Config) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, old: String, new: String, restart: bool| {
+            if old != new {
+                let note = if restart {
+                    " (takes effect after a restart)"
+                } else {
+                    ""
+                };
+                changes.push(format!("{}: {} -> {}{}", name, old, new, note));
+            }
+        };
+
+        compare(
+            "listen",
+            format!("{:?}", self.listen),
+            format!("{:?}", new.listen),
+            true,
+        );
+        compare(
+            "server",
+            format!("{:?}", self.server),
+            format!("{:?}", new.server),
+            true,
+        );
+        let (old_upstream, new_upstream) = (&self.upstream, &new.upstream);
+        compare(
+            "upstream.resolvers",
+            format!("{:?}", old_upstream.resolvers),
+            format!("{:?}", new_upstream.resolvers),
+            false,
+        );
+        compare(
+            "upstream.strategy",
+            format!("{:?}", old_upstream.strategy),
+            format!("{:?}", new_upstream.strategy),
+            false,
+        );
+        compare(
+            "upstream.timeout_ms",
+            old_upstream.timeout_ms.to_string(),
+            new_upstream.timeout_ms.to_string(),
+            false,
+        );
+        compare(
+            "upstream.retries",
+            old_upstream.retries.to_string(),
+            new_upstream.retries.to_string(),
+            false,
+        );
+        compare(
+            "upstream.deadline_ms",
+            old_upstream.deadline_ms.to_string(),
+            new_upstream.deadline_ms.to_string(),
+            false,
+        );
+        compare(
+            "cache.size",
+            self.cache.size.to_string(),
+            new.cache.size.to_string(),
+            false,
+        );
+        compare(
+            "hosts_files",
+            format!("{:?}", self.hosts_files),
+            format!("{:?}", new.hosts_files),
+            false,
+        );
+        compare(
+            "logging.level",
+            format!("{:?}", self.logging.level),
+            format!("{:?}", new.logging.level),
+            false,
+        );
+
+        let old_records: HashSet<&RecordConfig> = self.records.iter().collect();
+        let new_records: HashSet<&RecordConfig> = new.records.iter().collect();
+        let added = new_records.difference(&old_records).count();
+        let removed = old_records.difference(&new_records).count();
+        if added + removed > 0 {
+            changes.push(format!("records: {} added, {} removed", added, removed));
+        }
+
+        // Zone files are read again on every reload, so their contents are not compared
+        let old_zones: HashSet<&ZoneConfig> = self.zones.iter().collect();
+        let new_zones: HashSet<&ZoneConfig> = new.zones.iter().collect();
+        for zone in new_zones.difference(&old_zones) {
+            changes.push(format!(
+                "zones: added {} from {}",
+                zone.origin,
+                zone.file.display()
+            ));
+        }
+        for zone in old_zones.difference(&new_zones) {
+            changes.push(format!("zones: removed {}", zone.origin));
+        }
+
+        changes
+    }
+}
+
+impl RecordConfig {
+    fn to_answer(&self) -> Result<DnsAnswer, String> {
+        let rtype = RecordType::from_name(&self.rtype)
+            .ok_or_else(|| format!("Unsupported record type {}", self.rtype))?;
+        let name = qualify_name(&self.name, ".").map_err(|e| e.to_string())?;
+
+        let fields = split_fields(&self.value).map_err(|e| e.to_string())?;
+        let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+        let rdata = RData::from_presentation(rtype, &fields, ".")
+            .and_then(|data| data.to_bytes())
+            .map_err(|e| e.to_string())?;
+
+        Ok(DnsAnswer::new(
+            name,
+            rtype.to_u16(),
+            RecordClass::IN.to_u16(),
+            self.ttl,
+            rdata,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_question_and_answer::DnsQuestion;
+
+    #[test]
+    fn test_example_config() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.upstream.strategy, Strategy::RoundRobin);
+        assert_eq!(config.logging.level, LogLevel::Info);
+
+        let local = config.local_records().unwrap();
+        let question = DnsQuestion {
+            name: "router.lan".to_string(),
+            qtype: RecordType::A.to_u16(),
+            qclass: 1,
+        };
+        assert_eq!(
+            local.lookup(&question).unwrap()[0].rdata,
+            vec![192, 168, 1, 1]
+        );
+
+        let question = DnsQuestion {
+            name: "www.example.com".to_string(),
+            qtype: RecordType::A.to_u16(),
+            qclass: 1,
+        };
+        let zone_answer = local.answer(&question).unwrap();
+        assert!(zone_answer.authoritative);
+        assert_eq!(zone_answer.answers[0].ttl, 300);
+    }
+
+    #[test]
+    fn test_defaults_when_empty() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.upstream.timeout_ms, 2000);
+    }
+
+    #[test]
+    fn test_changes() {
+        let old: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        assert!(old.changes(&old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.listen.pop();
+        new.upstream.timeout_ms = 500;
+        new.records[0].value = "192.168.1.254".to_string();
+
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                "listen: [127.0.0.1:2053, [::1]:2053] -> [127.0.0.1:2053] \
+                 (takes effect after a restart)"
+                    .to_string(),
+                "upstream.timeout_ms: 2000 -> 500".to_string(),
+                "records: 1 added, 1 removed".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_config() {
+        assert!(toml::from_str::<Config>("[server]\nthreads = 4").is_err());
+        assert!(toml::from_str::<Config>("listen = [\"localhost\"]").is_err());
+
+        let zero_workers: Config = toml::from_str("[server]\nworkers = 0").unwrap();
+        assert!(zero_workers.validate().is_err());
+
+        let bad_record: Config =
+            toml::from_str("[[records]]\nname = \"a.lan\"\ntype = \"A\"\nvalue = \"10.0.0\"")
+                .unwrap();
+        assert!(matches!(
+            bad_record.validate(),
+            Err(ConfigError::Invalid(message)) if message.contains("a.lan")
+        ));
+    }
+}
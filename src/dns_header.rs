@@ -1,4 +1,6 @@
-#[derive(Debug)]
+use crate::error::DnsError;
+
+#[derive(Debug, Clone)]
 pub struct DnsHeader {
     pub id: u16,
     pub flags: u16,
@@ -17,11 +19,13 @@ pub struct DnsFlags {
     pub rd: bool,              // Recursion Desired
     pub ra: bool,              // Recursion Available
     pub z: u8,                 // Reserved (must be 0)
+    pub ad: bool,              // Authentic Data (RFC 4035)
+    pub cd: bool,              // Checking Disabled (RFC 4035)
     pub rcode: u8,             // Response code (0 = no error, 1 = format error, etc.)
 }
 
 impl DnsFlags {
-    pub fn to_u16(&self) -> u16 {
+    pub fn to_u16(self) -> u16 {
         let mut flags: u16 = 0;
         
         if self.qr { flags |= 1 << 15; }           // QR at bit 15
@@ -30,7 +34,9 @@ impl DnsFlags {
         if self.tc { flags |= 1 << 9; }            // TC at bit 9
         if self.rd { flags |= 1 << 8; }            // RD at bit 8
         if self.ra { flags |= 1 << 7; }            // RA at bit 7
-        flags |= (self.z as u16 & 0x7) << 4;       // Z at bits 4-6 (reserved)
+        flags |= (self.z as u16 & 0x1) << 6;       // Z at bit 6 (reserved)
+        if self.ad { flags |= 1 << 5; }            // AD at bit 5
+        if self.cd { flags |= 1 << 4; }            // CD at bit 4
         flags |= self.rcode as u16 & 0xF;          // RCODE at bits 0-3
         
         flags
@@ -44,16 +50,18 @@ impl DnsFlags {
             tc: (flags & (1 << 9)) != 0,
             rd: (flags & (1 << 8)) != 0,
             ra: (flags & (1 << 7)) != 0,
-            z: ((flags >> 4) & 0x7) as u8,
+            z: ((flags >> 6) & 0x1) as u8,
+            ad: (flags & (1 << 5)) != 0,
+            cd: (flags & (1 << 4)) != 0,
             rcode: (flags & 0xF) as u8,
         }
     }
 }
 
 impl DnsHeader {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DnsError> {
         if bytes.len() < 12 {
-            return Err("Buffer too small for DNS header");
+            return Err(DnsError::Truncated("DNS header"));
         }
 
         Ok(DnsHeader {
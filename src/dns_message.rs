@@ -1,70 +1,519 @@
 use crate::dns_header::{DnsFlags, DnsHeader};
-use crate::dns_question_and_answer::{DnsAnswer, DnsQuestion};
+use crate::dns_question_and_answer::{DnsAnswer, DnsQuestion, RecordType};
+use crate::edns::EdnsOpt;
+use crate::error::DnsError;
+use crate::message_writer::MessageWriter;
 
-/// Parse the DNS request from the buffer
-/// Takes an immutable borrow of the buffer, returns owned structures
-pub fn parse_request(buf: &[u8]) -> Result<(DnsHeader, Vec<DnsQuestion>), String> {
-    let header =
-        DnsHeader::from_bytes(&buf[0..12]).map_err(|e| format!("Failed to parse header: {}", e))?;
+/// Size of the fixed DNS header in bytes
+const DNS_HEADER_SIZE: usize = 12;
 
-    let mut questions = Vec::new();
-    let mut offset = 12; // Start after header
+/// Maximum size of a DNS message over plain UDP (RFC 1035)
+pub const MAX_UDP_PAYLOAD: usize = 512;
 
-    for _ in 0..header.question_count {
-        let (question, new_offset) = DnsQuestion::from_bytes(buf, offset)?;
-        questions.push(question);
+/// Maximum size of a DNS message over TCP (limited by the two-byte length prefix)
+pub const MAX_TCP_PAYLOAD: usize = 65535;
+
+/// Response code for a request we could not parse
+pub const RCODE_FORMERR: u16 = 1;
+
+/// Response code for a request we could not answer because of our own failure
+pub const RCODE_SERVFAIL: u16 = 2;
+
+/// Response code for a name that does not exist
+pub const RCODE_NXDOMAIN: u16 = 3;
+
+/// Response code for a request kind (opcode) we do not implement
+pub const RCODE_NOTIMP: u16 = 4;
+
+/// Response code for a request we will not answer, like a name outside our zones
+pub const RCODE_REFUSED: u16 = 5;
+
+/// Complete DNS message with all four record sections
+/// Header counts are derived from the section lengths when the message is encoded
+#[derive(Debug, Clone)]
+pub struct DnsMessage {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsAnswer>,
+    pub authorities: Vec<DnsAnswer>,
+    pub additionals: Vec<DnsAnswer>,
+}
+
+impl DnsMessage {
+    /// Create an empty message with the given header
+    pub fn new(header: DnsHeader) -> Self {
+        DnsMessage {
+            header,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    /// Parse a DNS message from the buffer
+    /// Takes an immutable borrow of the buffer, returns owned structures
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, DnsError> {
+        let header = DnsHeader::from_bytes(buf)?;
+
+        let mut offset = DNS_HEADER_SIZE; // Start after header
+
+        let mut questions = Vec::new();
+        for _ in 0..header.question_count {
+            let (question, new_offset) = DnsQuestion::from_bytes(buf, offset)?;
+            questions.push(question);
+            offset = new_offset;
+        }
+
+        let (answers, offset) = parse_records(buf, offset, header.answer_count)?;
+        let (authorities, offset) = parse_records(buf, offset, header.authority_count)?;
+        let (additionals, _) = parse_records(buf, offset, header.additional_count)?;
+
+        Ok(DnsMessage {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+
+    /// Encode the whole message without any size limit
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DnsError> {
+        build_response(self, usize::MAX)
+    }
+
+    /// EDNS OPT record from the additional section, if the sender used EDNS
+    pub fn edns(&self) -> Result<Option<EdnsOpt>, DnsError> {
+        EdnsOpt::from_additionals(&self.additionals)
+    }
+}
+
+/// Parse `count` resource records starting at `offset`
+/// Returns the records and the offset right after the last one
+fn parse_records(
+    buf: &[u8],
+    mut offset: usize,
+    count: u16,
+) -> Result<(Vec<DnsAnswer>, usize), DnsError> {
+    let mut records = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let (record, new_offset) = DnsAnswer::from_bytes(buf, offset)?;
+        records.push(record);
         offset = new_offset;
     }
 
-    Ok((header, questions))
+    Ok((records, offset))
 }
 
 /// Create response header based on request header
 /// Takes a reference to request header, returns owned response header
-pub fn create_response_header(request_header: &DnsHeader, answer_count: u16) -> DnsHeader {
+/// Section counts are left at zero; they are filled in when the response is built
+pub fn create_response_header(request_header: &DnsHeader) -> DnsHeader {
     let request_flags = DnsFlags::from_u16(request_header.flags);
 
     let response_flags = DnsFlags {
-        qr: true,                                             // This is a response
-        opcode: request_flags.opcode,                         // Echo opcode
-        aa: false,                                            // Not authoritative
-        tc: false,                                            // Not truncated
-        rd: request_flags.rd,                                 // Echo recursion desired
-        ra: false,                                            // Recursion not available
-        z: 0,                                                 // Reserved
-        rcode: if request_flags.opcode == 0 { 0 } else { 4 }, // 0 (no error) if standard query, else 4 (not implemented)
+        qr: true,                     // This is a response
+        opcode: request_flags.opcode, // Echo opcode
+        aa: false,                    // Not authoritative
+        tc: false,                    // Not truncated
+        rd: request_flags.rd,         // Echo recursion desired
+        ra: false,                    // Recursion not available
+        z: 0,                         // Reserved
+        ad: false,                    // Set from upstream when forwarding
+        cd: request_flags.cd,         // Echo checking disabled
+        rcode: if request_flags.opcode == 0 {
+            0
+        } else {
+            RCODE_NOTIMP as u8
+        }, // 0 (no error) if standard query, else 4 (not implemented)
     };
 
     DnsHeader {
-        id: request_header.id,                         // Echo request ID
-        flags: response_flags.to_u16(),                // Convert flags to u16
-        question_count: request_header.question_count, // Echo question count
-        answer_count,                                  // Number of answers we're providing
+        id: request_header.id,          // Echo request ID
+        flags: response_flags.to_u16(), // Convert flags to u16
+        question_count: 0,
+        answer_count: 0,
         authority_count: 0,
         additional_count: 0,
     }
 }
 
-/// Build the complete DNS response message
-pub fn build_response(
-    header: &DnsHeader,
+/// Set the response code in the header flags
+/// Only the lower 4 bits fit in the header; the rest travel in the EDNS OPT record
+pub fn set_rcode(header: &mut DnsHeader, rcode: u16) {
+    let mut flags = DnsFlags::from_u16(header.flags);
+    flags.rcode = (rcode & 0xF) as u8;
+    header.flags = flags.to_u16();
+}
+
+/// Build a response that carries only a response code
+/// Used whenever we cannot answer, so the client learns why instead of timing out
+/// `edns` is the client's OPT record; the extended part of `rcode` goes into our reply
+pub fn build_error_response(
+    request_header: &DnsHeader,
     questions: &[DnsQuestion],
-    answers: &[DnsAnswer],
-) -> Vec<u8> {
-    let mut response = Vec::new();
+    edns: Option<&EdnsOpt>,
+    rcode: u16,
+) -> Result<Vec<u8>, DnsError> {
+    let mut response = DnsMessage::new(create_response_header(request_header));
+    set_rcode(&mut response.header, rcode);
+    response.questions = questions.to_vec();
 
-    // Add header
-    response.extend_from_slice(&header.to_bytes());
+    if let Some(opt) = edns {
+        response
+            .additionals
+            .push(EdnsOpt::for_response(opt, rcode).to_record());
+    }
+
+    build_response(&response, MAX_UDP_PAYLOAD)
+}
+
+/// Build the complete DNS response message, keeping it within `max_size` bytes
+/// Records are dropped whole when they do not fit. Missing answer or authority
+/// records set the TC flag so the client retries over TCP; additional records
+/// are optional and are dropped silently (RFC 2181 section 9)
+/// The OPT record, if any, is always kept (RFC 6891 section 7)
+pub fn build_response(message: &DnsMessage, max_size: usize) -> Result<Vec<u8>, DnsError> {
+    let mut writer = MessageWriter::new();
+
+    // Header goes first; it is rewritten once the section counts are known
+    writer.write_bytes(&[0u8; DNS_HEADER_SIZE]);
 
     // Add questions (echo them back)
-    for question in questions {
-        response.extend(question.to_bytes());
+    for question in &message.questions {
+        question.write_to(&mut writer)?;
+    }
+
+    // Reserve room for the OPT record up front
+    let (opt_records, other_additionals): (Vec<&DnsAnswer>, Vec<&DnsAnswer>) = message
+        .additionals
+        .iter()
+        .partition(|record| record.rtype == RecordType::OPT.to_u16());
+    let mut opt_size = 0;
+    for opt in &opt_records {
+        opt_size += opt.to_bytes()?.len();
+    }
+    let max_size = max_size.saturating_sub(opt_size);
+
+    let mut truncated = false;
+    let answer_count = append_records(&mut writer, message.answers.iter(), max_size)?;
+    let mut authority_count = 0;
+    let mut additional_count = 0;
+
+    if answer_count < message.answers.len() {
+        truncated = true;
+    } else {
+        authority_count = append_records(&mut writer, message.authorities.iter(), max_size)?;
+        if authority_count < message.authorities.len() {
+            truncated = true;
+        } else {
+            additional_count =
+                append_records(&mut writer, other_additionals.into_iter(), max_size)?;
+        }
+    }
+
+    for opt in &opt_records {
+        opt.write_to(&mut writer)?;
+    }
+
+    let mut flags = DnsFlags::from_u16(message.header.flags);
+    flags.tc = flags.tc || truncated;
+
+    let response_header = DnsHeader {
+        id: message.header.id,
+        flags: flags.to_u16(),
+        question_count: message.questions.len() as u16,
+        answer_count: answer_count as u16,
+        authority_count: authority_count as u16,
+        additional_count: (additional_count + opt_records.len()) as u16,
+    };
+
+    let mut response = writer.into_bytes();
+    response[..DNS_HEADER_SIZE].copy_from_slice(&response_header.to_bytes());
+
+    Ok(response)
+}
+
+/// Append records to the message while it stays within `max_size` bytes
+/// A record that does not fit is rolled back; returns how many records were appended
+fn append_records<'a>(
+    writer: &mut MessageWriter,
+    records: impl Iterator<Item = &'a DnsAnswer>,
+    max_size: usize,
+) -> Result<usize, DnsError> {
+    let mut count = 0;
+
+    for record in records {
+        let mark = writer.len();
+        record.write_to(writer)?;
+        if writer.len() > max_size {
+            writer.truncate(mark);
+            break;
+        }
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_question_and_answer::encode_domain_name;
+    use crate::rdata::RData;
+
+    fn query_header() -> DnsHeader {
+        DnsHeader {
+            id: 0x1234,
+            flags: 0x0100,
+            question_count: 1,
+            answer_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        }
+    }
+
+    fn question() -> DnsQuestion {
+        DnsQuestion {
+            name: "example.com".to_string(),
+            qtype: 1,
+            qclass: 1,
+        }
+    }
+
+    fn answers(count: usize) -> Vec<DnsAnswer> {
+        (0..count)
+            .map(|i| DnsAnswer::new_a_record("example.com".to_string(), 60, [10, 0, 0, i as u8]))
+            .collect()
+    }
+
+    fn response(answers: Vec<DnsAnswer>) -> DnsMessage {
+        let mut message = DnsMessage::new(create_response_header(&query_header()));
+        message.questions.push(question());
+        message.answers = answers;
+        message
     }
 
-    // Add answers
-    for answer in answers {
-        response.extend(answer.to_bytes());
+    #[test]
+    fn test_build_response_fits() {
+        let response = build_response(&response(answers(3)), MAX_UDP_PAYLOAD).unwrap();
+
+        let parsed = DnsHeader::from_bytes(&response).unwrap();
+        assert_eq!(parsed.question_count, 1);
+        assert_eq!(parsed.answer_count, 3);
+        assert!(!DnsFlags::from_u16(parsed.flags).tc);
+    }
+
+    #[test]
+    fn test_build_response_truncates_whole_records() {
+        // Each A record for example.com takes 16 bytes once its name is compressed
+        let response = build_response(&response(answers(40)), MAX_UDP_PAYLOAD).unwrap();
+
+        assert!(response.len() <= MAX_UDP_PAYLOAD);
+
+        let parsed = DnsHeader::from_bytes(&response).unwrap();
+        assert!(DnsFlags::from_u16(parsed.flags).tc);
+        assert_eq!(parsed.answer_count, 30);
+        assert_eq!(response.len(), 12 + 17 + 30 * 16);
+    }
+
+    #[test]
+    fn test_build_response_compresses_names() {
+        let message = response(answers(10));
+        let response = build_response(&message, MAX_UDP_PAYLOAD).unwrap();
+
+        // Header + question + ten answers that point back at the question name
+        assert_eq!(response.len(), 12 + 17 + 10 * 16);
+
+        let parsed = DnsMessage::from_bytes(&response).unwrap();
+        assert_eq!(parsed.answers.len(), 10);
+        for (parsed, original) in parsed.answers.iter().zip(&message.answers) {
+            assert_eq!(parsed.name, "example.com");
+            assert_eq!(parsed.rdata, original.rdata);
+        }
+    }
+
+    #[test]
+    fn test_build_response_compresses_rdata_names() {
+        let mut message = response(Vec::new());
+        message.answers.push(DnsAnswer::new(
+            "example.com".to_string(),
+            RecordType::CNAME.to_u16(),
+            1,
+            300,
+            RData::CNAME("www.example.com".to_string())
+                .to_bytes()
+                .unwrap(),
+        ));
+        message.answers.push(DnsAnswer::new(
+            "www.example.com".to_string(),
+            RecordType::MX.to_u16(),
+            1,
+            300,
+            RData::MX {
+                preference: 10,
+                exchange: "mail.example.com".to_string(),
+            }
+            .to_bytes()
+            .unwrap(),
+        ));
+
+        let response = build_response(&message, MAX_UDP_PAYLOAD).unwrap();
+        let parsed = DnsMessage::from_bytes(&response).unwrap();
+
+        assert_eq!(parsed.answers[1].name, "www.example.com");
+        assert_eq!(
+            parsed.answers[0].data().unwrap(),
+            RData::CNAME("www.example.com".to_string())
+        );
+        assert_eq!(
+            parsed.answers[1].data().unwrap(),
+            RData::MX {
+                preference: 10,
+                exchange: "mail.example.com".to_string(),
+            }
+        );
+
+        // CNAME RDATA is "www" + pointer to the question name, MX owner points at it
+        let uncompressed: usize = message
+            .answers
+            .iter()
+            .map(|answer| encode_domain_name(&answer.name).unwrap().len() + 10 + answer.rdata.len())
+            .sum();
+        assert_eq!(response.len(), 12 + 17 + 2 + 10 + 6 + 2 + 10 + 2 + 7);
+        assert!(response.len() < 12 + 17 + uncompressed);
+    }
+
+    #[test]
+    fn test_build_response_keeps_opt_record() {
+        let opt = EdnsOpt::for_query();
+        let mut message = response(answers(40));
+        message.additionals.push(opt.to_record());
+        let response = build_response(&message, MAX_UDP_PAYLOAD).unwrap();
+
+        assert!(response.len() <= MAX_UDP_PAYLOAD);
+
+        // The 11-byte OPT record costs one answer compared to the plain response
+        let parsed = DnsMessage::from_bytes(&response).unwrap();
+        assert!(DnsFlags::from_u16(parsed.header.flags).tc);
+        assert_eq!(parsed.answers.len(), 29);
+        assert_eq!(parsed.edns().unwrap(), Some(opt));
     }
 
-    response
+    #[test]
+    fn test_build_response_drops_additionals_without_tc() {
+        let mut message = response(answers(30));
+        message.additionals = answers(5);
+        let response = build_response(&message, MAX_UDP_PAYLOAD).unwrap();
+
+        let parsed = DnsMessage::from_bytes(&response).unwrap();
+        assert!(!DnsFlags::from_u16(parsed.header.flags).tc);
+        assert_eq!(parsed.answers.len(), 30);
+        assert!(parsed.additionals.is_empty());
+    }
+
+    #[test]
+    fn test_message_roundtrip_all_sections() {
+        let mut message = response(answers(2));
+        message.authorities.push(DnsAnswer::new(
+            "example.com".to_string(),
+            RecordType::NS.to_u16(),
+            1,
+            3600,
+            vec![2, b'n', b's', 0],
+        ));
+        message.additionals.push(DnsAnswer::new_a_record(
+            "ns".to_string(),
+            3600,
+            [192, 0, 2, 53],
+        ));
+
+        let bytes = message.to_bytes().unwrap();
+        let parsed = DnsMessage::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed.header.id, 0x1234);
+        assert_eq!(parsed.header.question_count, 1);
+        assert_eq!(parsed.header.answer_count, 2);
+        assert_eq!(parsed.header.authority_count, 1);
+        assert_eq!(parsed.header.additional_count, 1);
+        assert_eq!(parsed.authorities[0].rdata, vec![2, b'n', b's', 0]);
+        assert_eq!(parsed.additionals[0].name, "ns");
+        assert_eq!(parsed.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_parse_errors_map_to_formerr() {
+        let mut bytes = query_header().to_bytes().to_vec();
+        bytes.extend([7, b'e', b'x', b'a']);
+
+        let error = DnsMessage::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(error, DnsError::Truncated(_)));
+        assert_eq!(error.rcode(), RCODE_FORMERR);
+    }
+
+    #[test]
+    fn test_build_error_response() {
+        let response =
+            build_error_response(&query_header(), &[question()], None, RCODE_SERVFAIL).unwrap();
+
+        let parsed = DnsMessage::from_bytes(&response).unwrap();
+        let flags = DnsFlags::from_u16(parsed.header.flags);
+        assert_eq!(parsed.header.id, 0x1234);
+        assert!(flags.qr);
+        assert!(flags.rd);
+        assert_eq!(flags.rcode as u16, RCODE_SERVFAIL);
+        assert_eq!(parsed.questions.len(), 1);
+        assert!(parsed.answers.is_empty());
+        assert!(parsed.additionals.is_empty());
+    }
+
+    #[test]
+    fn test_build_error_response_with_extended_rcode() {
+        let request_opt = EdnsOpt {
+            version: 1,
+            ..EdnsOpt::for_query()
+        };
+        let response =
+            build_error_response(&query_header(), &[question()], Some(&request_opt), 16).unwrap();
+
+        let parsed = DnsMessage::from_bytes(&response).unwrap();
+        assert_eq!(DnsFlags::from_u16(parsed.header.flags).rcode, 0);
+        assert_eq!(parsed.edns().unwrap().unwrap().extended_rcode, 1);
+    }
+
+    #[test]
+    fn test_unknown_opcode_gets_notimp() {
+        let mut header = query_header();
+        header.flags |= 2 << 11; // STATUS
+
+        let flags = DnsFlags::from_u16(create_response_header(&header).flags);
+        assert_eq!(flags.opcode, 2);
+        assert_eq!(flags.rcode as u16, RCODE_NOTIMP);
+    }
+
+    #[test]
+    fn test_response_header_flags() {
+        let mut header = query_header();
+        header.flags |= 0x0030; // AD and CD
+
+        let flags = DnsFlags::from_u16(create_response_header(&header).flags);
+        assert!(flags.qr);
+        assert!(flags.cd);
+        assert!(!flags.ad);
+        assert!(!flags.ra);
+        assert_eq!(flags.z, 0);
+    }
+
+    #[test]
+    fn test_set_rcode() {
+        let mut header = create_response_header(&query_header());
+        set_rcode(&mut header, RCODE_FORMERR);
+        assert_eq!(DnsFlags::from_u16(header.flags).rcode, 1);
+
+        // BADVERS (16) leaves zero in the header, the rest goes into OPT
+        set_rcode(&mut header, 16);
+        assert_eq!(DnsFlags::from_u16(header.flags).rcode, 0);
+    }
 }
@@ -1,3 +1,7 @@
+use crate::error::DnsError;
+use crate::message_writer::MessageWriter;
+use crate::rdata::RData;
+
 /// DNS Question Section
 /// Format: QNAME + QTYPE (2 bytes) + QCLASS (2 bytes)
 #[derive(Debug, Clone)]
@@ -11,19 +15,20 @@ pub struct DnsQuestion {
 /// Format: NAME + TYPE (2 bytes) + CLASS (2 bytes) + TTL (4 bytes) + RDLENGTH (2 bytes) + RDATA
 #[derive(Debug, Clone)]
 pub struct DnsAnswer {
-    pub name: String,   // Domain name
-    pub rtype: u16,     // Record type (A, AAAA, CNAME, etc.)
-    pub rclass: u16,    // Record class (usually IN for Internet)
-    pub ttl: u32,       // Time to live in seconds
-    pub rdlength: u16,  // Length of RDATA field
+    pub name: String, // Domain name
+    pub rtype: u16,   // Record type (A, AAAA, CNAME, etc.)
+    pub rclass: u16,  // Record class (usually IN for Internet)
+    pub ttl: u32,     // Time to live in seconds
+    #[allow(dead_code)] // Recomputed from RDATA when the record is written
+    pub rdlength: u16, // Length of RDATA field
     pub rdata: Vec<u8>, // Resource data (format depends on record type)
 }
 
 /// Common DNS record types
-/// I use only A and AAAA for this implementation
+/// Their RDATA can be decoded with `RData`
 /// Other types can be added as needed
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum RecordType {
     A = 1,     // IPv4 address
     NS = 2,    // Name server
@@ -36,8 +41,7 @@ pub enum RecordType {
     OPT = 41,  // EDNS0 option
 }
 
-/// Currently I forward record types as-is, so these conversion functions are not used
-/// However, they can be useful for future extensions
+/// Not every type is constructed by the server itself, hence the dead_code allowance
 #[allow(dead_code)]
 impl RecordType {
     pub fn from_u16(value: u16) -> Option<Self> {
@@ -58,6 +62,23 @@ impl RecordType {
     pub fn to_u16(self) -> u16 {
         self as u16
     }
+
+    /// Look up a type by its mnemonic (e.g. "AAAA"), ignoring case
+    /// OPT is not a record type one writes down, so it is not recognized
+    pub fn from_name(name: &str) -> Option<Self> {
+        let record_type = match name.to_ascii_uppercase().as_str() {
+            "A" => RecordType::A,
+            "NS" => RecordType::NS,
+            "CNAME" => RecordType::CNAME,
+            "SOA" => RecordType::SOA,
+            "PTR" => RecordType::PTR,
+            "MX" => RecordType::MX,
+            "TXT" => RecordType::TXT,
+            "AAAA" => RecordType::AAAA,
+            _ => return None,
+        };
+        Some(record_type)
+    }
 }
 
 /// Common DNS classes
@@ -96,11 +117,11 @@ impl RecordClass {
 impl DnsQuestion {
     /// Parse a DNS question from bytes starting at the given offset
     /// Returns the question and the new offset after parsing
-    pub fn from_bytes(bytes: &[u8], offset: usize) -> Result<(Self, usize), String> {
+    pub fn from_bytes(bytes: &[u8], offset: usize) -> Result<(Self, usize), DnsError> {
         let (name, new_offset) = parse_domain_name(bytes, offset)?;
 
         if new_offset + 4 > bytes.len() {
-            return Err("Buffer too small for question type and class".to_string());
+            return Err(DnsError::Truncated("question type and class"));
         }
 
         let qtype = u16::from_be_bytes([bytes[new_offset], bytes[new_offset + 1]]);
@@ -117,28 +138,35 @@ impl DnsQuestion {
     }
 
     /// Convert the question to bytes
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    /// Messages use `write_to` instead; this is handy for tests and tooling
+    #[allow(dead_code)]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DnsError> {
+        let mut writer = MessageWriter::new();
+        self.write_to(&mut writer)?;
+        Ok(writer.into_bytes())
+    }
 
+    /// Append the question to a message, compressing the name against earlier ones
+    pub fn write_to(&self, writer: &mut MessageWriter) -> Result<(), DnsError> {
         // Encode domain name
-        bytes.extend(encode_domain_name(&self.name));
+        writer.write_name(&self.name)?;
 
         // Add type and class
-        bytes.extend(&self.qtype.to_be_bytes());
-        bytes.extend(&self.qclass.to_be_bytes());
+        writer.write_u16(self.qtype);
+        writer.write_u16(self.qclass);
 
-        bytes
+        Ok(())
     }
 }
 
 impl DnsAnswer {
     /// Parse a DNS answer/resource record from bytes starting at the given offset
     /// Returns the answer and the new offset after parsing
-    pub fn from_bytes(bytes: &[u8], offset: usize) -> Result<(Self, usize), String> {
+    pub fn from_bytes(bytes: &[u8], offset: usize) -> Result<(Self, usize), DnsError> {
         let (name, new_offset) = parse_domain_name(bytes, offset)?;
 
         if new_offset + 10 > bytes.len() {
-            return Err("Buffer too small for answer fields".to_string());
+            return Err(DnsError::Truncated("answer fields"));
         }
 
         let rtype = u16::from_be_bytes([bytes[new_offset], bytes[new_offset + 1]]);
@@ -153,41 +181,58 @@ impl DnsAnswer {
 
         let data_offset = new_offset + 10;
         if data_offset + rdlength as usize > bytes.len() {
-            return Err("Buffer too small for RDATA".to_string());
+            return Err(DnsError::Truncated("RDATA"));
         }
 
-        let rdata = bytes[data_offset..data_offset + rdlength as usize].to_vec();
+        // Decode and re-encode so that names compressed against this message
+        // are expanded and stay valid once the record is copied elsewhere
+        let rdata = RData::from_bytes(bytes, data_offset, rdlength as usize, rtype)?.to_bytes()?;
 
         Ok((
-            DnsAnswer {
-                name,
-                rtype,
-                rclass,
-                ttl,
-                rdlength,
-                rdata,
-            },
+            DnsAnswer::new(name, rtype, rclass, ttl, rdata),
             data_offset + rdlength as usize,
         ))
     }
 
     /// Convert the answer to bytes
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DnsError> {
+        let mut writer = MessageWriter::new();
+        self.write_to(&mut writer)?;
+        Ok(writer.into_bytes())
+    }
 
+    /// Append the record to a message, compressing its owner name and,
+    /// for well-known types, the names inside RDATA
+    pub fn write_to(&self, writer: &mut MessageWriter) -> Result<(), DnsError> {
         // Encode domain name
-        bytes.extend(encode_domain_name(&self.name));
+        writer.write_name(&self.name)?;
 
-        // Add type, class, TTL, and data length
-        bytes.extend(&self.rtype.to_be_bytes());
-        bytes.extend(&self.rclass.to_be_bytes());
-        bytes.extend(&self.ttl.to_be_bytes());
-        bytes.extend(&self.rdlength.to_be_bytes());
+        // Add type, class and TTL
+        writer.write_u16(self.rtype);
+        writer.write_u16(self.rclass);
+        writer.write_u32(self.ttl);
+
+        // Data length is only known once RDATA has been written
+        let rdlength_offset = writer.len();
+        writer.write_u16(0);
+
+        // Add resource data, falling back to the stored bytes if they do not decode
+        match RData::from_bytes(&self.rdata, 0, self.rdata.len(), self.rtype) {
+            Ok(data) => data.write_to(writer)?,
+            Err(_) => writer.write_bytes(&self.rdata),
+        }
 
-        // Add resource data
-        bytes.extend(&self.rdata);
+        let rdlength = writer.len() - rdlength_offset - 2;
+        let rdlength = u16::try_from(rdlength).map_err(|_| DnsError::MessageTooLarge(rdlength))?;
+        writer.patch_u16(rdlength_offset, rdlength);
 
-        bytes
+        Ok(())
+    }
+
+    /// Decode the RDATA according to the record type
+    /// Meant for tooling that inspects records; the server itself forwards RDATA as-is
+    pub fn data(&self) -> Result<RData, DnsError> {
+        RData::from_bytes(&self.rdata, 0, self.rdata.len(), self.rtype)
     }
 
     /// Create a new DNS answer with the given parameters
@@ -229,19 +274,24 @@ impl DnsAnswer {
     }
 }
 
+/// Longest domain name allowed in wire format, length bytes included (RFC 1035)
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Longest single label allowed (RFC 1035)
+const MAX_LABEL_LENGTH: usize = 63;
+
 /// Parse a domain name from DNS message format
 /// Supports DNS name compression (pointers)
 /// Returns the parsed domain name and the new offset
-pub fn parse_domain_name(bytes: &[u8], mut offset: usize) -> Result<(String, usize), String> {
+pub fn parse_domain_name(bytes: &[u8], mut offset: usize) -> Result<(String, usize), DnsError> {
     let mut labels = Vec::new();
     let mut jumped = false;
     let mut jump_offset = offset;
-    let max_jumps = 5; // Prevent infinite loops
-    let mut jumps = 0;
+    let mut wire_length = 0;
 
     loop {
         if offset >= bytes.len() {
-            return Err("Offset out of bounds while parsing domain name".to_string());
+            return Err(DnsError::Truncated("domain name"));
         }
 
         let length = bytes[offset];
@@ -249,28 +299,43 @@ pub fn parse_domain_name(bytes: &[u8], mut offset: usize) -> Result<(String, usi
         // Check if this is a pointer (compression)
         if (length & 0xC0) == 0xC0 {
             if offset + 1 >= bytes.len() {
-                return Err("Incomplete pointer in domain name".to_string());
+                return Err(DnsError::Truncated("compression pointer"));
             }
 
             // Pointer: the next 14 bits indicate the offset
-            let pointer = u16::from_be_bytes([bytes[offset] & 0x3F, bytes[offset + 1]]);
+            let pointer = u16::from_be_bytes([bytes[offset] & 0x3F, bytes[offset + 1]]) as usize;
+
+            if pointer >= bytes.len() {
+                return Err(DnsError::BadPointer(pointer));
+            }
+
+            // Pointers must refer to an earlier part of the message,
+            // which also rules out loops
+            if pointer >= offset {
+                return Err(DnsError::PointerLoop(offset));
+            }
 
             if !jumped {
                 jump_offset = offset + 2;
             }
 
-            offset = pointer as usize;
+            offset = pointer;
             jumped = true;
-            jumps += 1;
-
-            if jumps > max_jumps {
-                return Err("Too many jumps while parsing domain name".to_string());
-            }
             continue;
         }
 
+        // 0x40 and 0x80 prefixes are reserved / obsolete label types
+        if (length & 0xC0) != 0 {
+            return Err(DnsError::BadLabelType(length));
+        }
+
         // Move past the length byte
         offset += 1;
+        wire_length += 1 + length as usize;
+
+        if wire_length > MAX_NAME_LENGTH {
+            return Err(DnsError::NameTooLong(labels.join(".")));
+        }
 
         // Check for end of name
         if length == 0 {
@@ -279,11 +344,11 @@ pub fn parse_domain_name(bytes: &[u8], mut offset: usize) -> Result<(String, usi
 
         // Read the label
         if offset + length as usize > bytes.len() {
-            return Err("Label extends beyond buffer".to_string());
+            return Err(DnsError::Truncated("domain label"));
         }
 
         let label = std::str::from_utf8(&bytes[offset..offset + length as usize])
-            .map_err(|_| "Invalid UTF-8 in domain label".to_string())?;
+            .map_err(|_| DnsError::BadUtf8)?;
 
         labels.push(label.to_string());
         offset += length as usize;
@@ -302,13 +367,13 @@ pub fn parse_domain_name(bytes: &[u8], mut offset: usize) -> Result<(String, usi
 /// Encode a domain name to DNS message format
 /// Format: length-prefixed labels terminated with a null byte
 /// Example: "example.com" -> [7]example[3]com[0]
-pub fn encode_domain_name(name: &str) -> Vec<u8> {
+pub fn encode_domain_name(name: &str) -> Result<Vec<u8>, DnsError> {
     let mut encoded = Vec::new();
 
     // Handle root domain
     if name == "." {
         encoded.push(0);
-        return encoded;
+        return Ok(encoded);
     }
 
     // Split domain into labels and encode each
@@ -317,20 +382,32 @@ pub fn encode_domain_name(name: &str) -> Vec<u8> {
             continue;
         }
 
-        let label_bytes = label.as_bytes();
-        if label_bytes.len() > 63 {
-            // DNS labels are limited to 63 bytes
-            panic!("Label too long: {}", label);
-        }
-
-        encoded.push(label_bytes.len() as u8);
-        encoded.extend_from_slice(label_bytes);
+        encoded.extend(encode_label(label)?);
     }
 
     // Null terminator
     encoded.push(0);
 
-    encoded
+    if encoded.len() > MAX_NAME_LENGTH {
+        return Err(DnsError::NameTooLong(name.to_string()));
+    }
+
+    Ok(encoded)
+}
+
+/// Encode a single length-prefixed label
+pub fn encode_label(label: &str) -> Result<Vec<u8>, DnsError> {
+    let label_bytes = label.as_bytes();
+    if label_bytes.len() > MAX_LABEL_LENGTH {
+        // DNS labels are limited to 63 bytes
+        return Err(DnsError::LabelTooLong(label.to_string()));
+    }
+
+    let mut encoded = Vec::with_capacity(label_bytes.len() + 1);
+    encoded.push(label_bytes.len() as u8);
+    encoded.extend_from_slice(label_bytes);
+
+    Ok(encoded)
 }
 
 #[cfg(test)]
@@ -339,7 +416,7 @@ mod tests {
 
     #[test]
     fn test_encode_domain_name() {
-        let encoded = encode_domain_name("example.com");
+        let encoded = encode_domain_name("example.com").unwrap();
         assert_eq!(
             encoded,
             vec![7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0]
@@ -348,7 +425,7 @@ mod tests {
 
     #[test]
     fn test_encode_root_domain() {
-        let encoded = encode_domain_name(".");
+        let encoded = encode_domain_name(".").unwrap();
         assert_eq!(encoded, vec![0]);
     }
 
@@ -370,7 +447,7 @@ mod tests {
             qclass: RecordClass::IN.to_u16(),
         };
 
-        let bytes = question.to_bytes();
+        let bytes = question.to_bytes().unwrap();
         let (parsed, _) = DnsQuestion::from_bytes(&bytes, 0).unwrap();
 
         assert_eq!(parsed.name, question.name);
@@ -393,7 +470,7 @@ mod tests {
     fn test_dns_answer_roundtrip() {
         let answer = DnsAnswer::new_a_record("example.com".to_string(), 60, [192, 168, 1, 1]);
 
-        let bytes = answer.to_bytes();
+        let bytes = answer.to_bytes().unwrap();
         let (parsed, _) = DnsAnswer::from_bytes(&bytes, 0).unwrap();
 
         assert_eq!(parsed.name, answer.name);
@@ -402,4 +479,97 @@ mod tests {
         assert_eq!(parsed.ttl, answer.ttl);
         assert_eq!(parsed.rdata, answer.rdata);
     }
+
+    #[test]
+    fn test_dns_answer_expands_compressed_rdata() {
+        // Owner "example.com" at offset 0, then a CNAME to "www" + pointer back to it
+        let mut bytes = encode_domain_name("example.com").unwrap();
+        bytes.extend([0xC0, 0x00]);
+        bytes.extend(&RecordType::CNAME.to_u16().to_be_bytes());
+        bytes.extend(&RecordClass::IN.to_u16().to_be_bytes());
+        bytes.extend(&300u32.to_be_bytes());
+        bytes.extend(&6u16.to_be_bytes());
+        bytes.extend([3, b'w', b'w', b'w', 0xC0, 0x00]);
+
+        let (parsed, offset) = DnsAnswer::from_bytes(&bytes, 13).unwrap();
+        assert_eq!(offset, bytes.len());
+        assert_eq!(parsed.rdata, encode_domain_name("www.example.com").unwrap());
+        assert_eq!(parsed.rdlength, 17);
+        assert_eq!(
+            parsed.data().unwrap(),
+            RData::CNAME("www.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_encode_label_too_long() {
+        let label = "a".repeat(64);
+        assert!(matches!(
+            encode_domain_name(&format!("{}.com", label)),
+            Err(DnsError::LabelTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_name_too_long() {
+        let name = vec!["a".repeat(63); 4].join(".");
+        assert!(matches!(
+            encode_domain_name(&name),
+            Err(DnsError::NameTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_domain_name_errors() {
+        // Truncated label
+        assert!(matches!(
+            parse_domain_name(&[7, b'e', b'x'], 0),
+            Err(DnsError::Truncated(_))
+        ));
+
+        // Pointer to itself
+        assert!(matches!(
+            parse_domain_name(&[0xC0, 0x00], 0),
+            Err(DnsError::PointerLoop(0))
+        ));
+
+        // Two pointers referring to each other
+        assert!(matches!(
+            parse_domain_name(&[0xC0, 0x02, 0xC0, 0x00], 2),
+            Err(DnsError::PointerLoop(_))
+        ));
+
+        // Pointer past the end of the message
+        assert!(matches!(
+            parse_domain_name(&[0, 0xC0, 0x10], 1),
+            Err(DnsError::BadPointer(16))
+        ));
+
+        // Reserved label type
+        assert!(matches!(
+            parse_domain_name(&[0x41, b'a', 0], 0),
+            Err(DnsError::BadLabelType(0x41))
+        ));
+
+        // Invalid UTF-8
+        assert!(matches!(
+            parse_domain_name(&[1, 0xFF, 0], 0),
+            Err(DnsError::BadUtf8)
+        ));
+    }
+
+    #[test]
+    fn test_parse_domain_name_too_long() {
+        let mut bytes = Vec::new();
+        for _ in 0..5 {
+            bytes.push(63);
+            bytes.extend([b'a'; 63]);
+        }
+        bytes.push(0);
+
+        assert!(matches!(
+            parse_domain_name(&bytes, 0),
+            Err(DnsError::NameTooLong(_))
+        ));
+    }
 }
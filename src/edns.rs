@@ -0,0 +1,236 @@
+use crate::dns_message::MAX_UDP_PAYLOAD;
+use crate::dns_question_and_answer::{DnsAnswer, RecordType};
+use crate::error::DnsError;
+
+/// The only EDNS version we implement (RFC 6891)
+pub const EDNS_VERSION: u8 = 0;
+
+/// UDP payload size we advertise to clients and upstreams
+/// 1232 avoids IP fragmentation on most paths (DNS flag day 2020)
+pub const SERVER_UDP_PAYLOAD: u16 = 1232;
+
+/// Extended RCODE returned when the client asks for an EDNS version we do not support
+pub const RCODE_BADVERS: u16 = 16;
+
+/// Single EDNS option carried in the OPT RDATA
+/// Format: OPTION-CODE (2 bytes) + OPTION-LENGTH (2 bytes) + OPTION-DATA
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// EDNS0 OPT pseudo-record
+/// The fixed record fields are reused: CLASS holds the UDP payload size,
+/// TTL holds the extended RCODE, version and the DO flag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOpt {
+    pub udp_payload_size: u16, // Largest UDP response the sender can reassemble
+    pub extended_rcode: u8,    // Upper 8 bits of the 12-bit RCODE
+    pub version: u8,           // EDNS version (0 is the only defined one)
+    pub dnssec_ok: bool,       // DO bit: sender understands DNSSEC records
+    pub options: Vec<EdnsOption>,
+}
+
+impl EdnsOpt {
+    /// Find and parse the OPT record among the additional records of a message
+    /// Returns None when the sender does not use EDNS, and an error for a malformed
+    /// OPT record or more than one of them (both are FORMERR per RFC 6891)
+    pub fn from_additionals(records: &[DnsAnswer]) -> Result<Option<Self>, DnsError> {
+        let mut opt_records = records
+            .iter()
+            .filter(|record| record.rtype == RecordType::OPT.to_u16());
+
+        let Some(record) = opt_records.next() else {
+            return Ok(None);
+        };
+
+        if opt_records.next().is_some() {
+            return Err(DnsError::BadEdns(
+                "More than one OPT record in message".to_string(),
+            ));
+        }
+
+        Self::from_record(record).map(Some)
+    }
+
+    /// Decode an OPT pseudo-record
+    pub fn from_record(record: &DnsAnswer) -> Result<Self, DnsError> {
+        if record.name != "." {
+            return Err(DnsError::BadEdns(format!(
+                "OPT record owner must be root, got {}",
+                record.name
+            )));
+        }
+
+        let ttl = record.ttl.to_be_bytes();
+        let options = parse_options(&record.rdata)?;
+
+        Ok(EdnsOpt {
+            udp_payload_size: record.rclass,
+            extended_rcode: ttl[0],
+            version: ttl[1],
+            dnssec_ok: (ttl[2] & 0x80) != 0,
+            options,
+        })
+    }
+
+    /// Encode as an OPT pseudo-record for the additional section
+    pub fn to_record(&self) -> DnsAnswer {
+        let ttl = u32::from_be_bytes([
+            self.extended_rcode,
+            self.version,
+            if self.dnssec_ok { 0x80 } else { 0 },
+            0,
+        ]);
+
+        let mut rdata = Vec::new();
+        for option in &self.options {
+            rdata.extend(&option.code.to_be_bytes());
+            rdata.extend(&(option.data.len() as u16).to_be_bytes());
+            rdata.extend(&option.data);
+        }
+
+        DnsAnswer::new(
+            ".".to_string(),
+            RecordType::OPT.to_u16(),
+            self.udp_payload_size,
+            ttl,
+            rdata,
+        )
+    }
+
+    /// Build the OPT record we send back to a client that used EDNS
+    /// `rcode` is the full 12-bit response code; only its upper 8 bits end up here
+    pub fn for_response(request: &EdnsOpt, rcode: u16) -> Self {
+        EdnsOpt {
+            udp_payload_size: SERVER_UDP_PAYLOAD,
+            extended_rcode: (rcode >> 4) as u8,
+            version: EDNS_VERSION,
+            dnssec_ok: request.dnssec_ok, // DO is echoed back (RFC 3225)
+            options: Vec::new(),
+        }
+    }
+
+    /// Build the OPT record we attach to queries sent upstream
+    pub fn for_query() -> Self {
+        EdnsOpt {
+            udp_payload_size: SERVER_UDP_PAYLOAD,
+            extended_rcode: 0,
+            version: EDNS_VERSION,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Largest UDP response we may send to the client that sent this OPT record
+    /// Values below 512 are treated as 512, and we never exceed our own limit
+    pub fn max_response_size(&self) -> usize {
+        (self.udp_payload_size as usize).clamp(MAX_UDP_PAYLOAD, SERVER_UDP_PAYLOAD as usize)
+    }
+}
+
+/// Parse the option list stored in OPT RDATA
+fn parse_options(rdata: &[u8]) -> Result<Vec<EdnsOption>, DnsError> {
+    let mut options = Vec::new();
+    let mut offset = 0;
+
+    while offset < rdata.len() {
+        if offset + 4 > rdata.len() {
+            return Err(DnsError::BadEdns(
+                "Buffer too small for option header".to_string(),
+            ));
+        }
+
+        let code = u16::from_be_bytes([rdata[offset], rdata[offset + 1]]);
+        let length = u16::from_be_bytes([rdata[offset + 2], rdata[offset + 3]]) as usize;
+        offset += 4;
+
+        if offset + length > rdata.len() {
+            return Err(DnsError::BadEdns("Option extends beyond RDATA".to_string()));
+        }
+
+        options.push(EdnsOption {
+            code,
+            data: rdata[offset..offset + length].to_vec(),
+        });
+        offset += length;
+    }
+
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opt_record_roundtrip() {
+        let opt = EdnsOpt {
+            udp_payload_size: 4096,
+            extended_rcode: 1,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10, // COOKIE
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        };
+
+        let record = opt.to_record();
+        assert_eq!(record.rclass, 4096);
+        assert_eq!(record.ttl, 0x0100_8000);
+
+        let bytes = record.to_bytes().unwrap();
+        let (parsed_record, _) = DnsAnswer::from_bytes(&bytes, 0).unwrap();
+        assert_eq!(EdnsOpt::from_record(&parsed_record).unwrap(), opt);
+    }
+
+    #[test]
+    fn test_from_additionals() {
+        assert_eq!(EdnsOpt::from_additionals(&[]).unwrap(), None);
+
+        let record = EdnsOpt::for_query().to_record();
+        let parsed = EdnsOpt::from_additionals(std::slice::from_ref(&record))
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.udp_payload_size, SERVER_UDP_PAYLOAD);
+
+        assert!(EdnsOpt::from_additionals(&[record.clone(), record]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_options() {
+        let mut record = EdnsOpt::for_query().to_record();
+        record.rdata = vec![0, 10, 0, 8, 1, 2];
+        assert!(EdnsOpt::from_record(&record).is_err());
+    }
+
+    #[test]
+    fn test_max_response_size() {
+        let mut opt = EdnsOpt::for_query();
+
+        opt.udp_payload_size = 100;
+        assert_eq!(opt.max_response_size(), MAX_UDP_PAYLOAD);
+
+        opt.udp_payload_size = 1000;
+        assert_eq!(opt.max_response_size(), 1000);
+
+        opt.udp_payload_size = 65000;
+        assert_eq!(opt.max_response_size(), SERVER_UDP_PAYLOAD as usize);
+    }
+
+    #[test]
+    fn test_response_carries_badvers() {
+        let request = EdnsOpt {
+            version: 1,
+            dnssec_ok: true,
+            ..EdnsOpt::for_query()
+        };
+
+        let response = EdnsOpt::for_response(&request, RCODE_BADVERS);
+        assert_eq!(response.extended_rcode, 1);
+        assert_eq!(response.version, EDNS_VERSION);
+        assert!(response.dnssec_ok);
+    }
+}
@@ -0,0 +1,109 @@
+use thiserror::Error;
+
+use crate::dns_message::{RCODE_FORMERR, RCODE_SERVFAIL};
+
+/// Errors raised while parsing, encoding or resolving DNS messages
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("Buffer too small for {0}")]
+    Truncated(&'static str),
+
+    #[error("Compression pointer to offset {0} is outside the message")]
+    BadPointer(usize),
+
+    #[error("Compression pointer at offset {0} does not point backwards")]
+    PointerLoop(usize),
+
+    #[error("Unsupported label type {0:#04x}")]
+    BadLabelType(u8),
+
+    #[error("Label too long: {0}")]
+    LabelTooLong(String),
+
+    #[error("Domain name too long: {0}")]
+    NameTooLong(String),
+
+    #[error("Invalid UTF-8 in domain label")]
+    BadUtf8,
+
+    #[error("Malformed RDATA: {0}")]
+    BadRdata(String),
+
+    #[error("Malformed EDNS: {0}")]
+    BadEdns(String),
+
+    #[error("Message too large: {0} bytes")]
+    MessageTooLarge(usize),
+
+    #[error("{context}: {source}")]
+    Io {
+        context: &'static str,
+        source: std::io::Error,
+    },
+
+    #[error("Upstream failure: {0}")]
+    Upstream(String),
+
+    #[error("No answer from resolver {resolver} after {attempts} attempt(s)")]
+    UpstreamTimeout { resolver: String, attempts: u32 },
+}
+
+impl DnsError {
+    /// Attach a description of what we were doing to an I/O error
+    pub fn io(context: &'static str) -> impl FnOnce(std::io::Error) -> Self {
+        move |source| DnsError::Io { context, source }
+    }
+
+    /// Response code to send when this error stops us from answering
+    /// Anything wrong with the request itself is FORMERR, everything else is on us
+    pub fn rcode(&self) -> u16 {
+        match self {
+            DnsError::Truncated(_)
+            | DnsError::BadPointer(_)
+            | DnsError::PointerLoop(_)
+            | DnsError::BadLabelType(_)
+            | DnsError::LabelTooLong(_)
+            | DnsError::NameTooLong(_)
+            | DnsError::BadUtf8
+            | DnsError::BadRdata(_)
+            | DnsError::BadEdns(_) => RCODE_FORMERR,
+            DnsError::MessageTooLarge(_)
+            | DnsError::Io { .. }
+            | DnsError::Upstream(_)
+            | DnsError::UpstreamTimeout { .. } => RCODE_SERVFAIL,
+        }
+    }
+}
+
+/// Errors in the configuration file or in the settings it combines into
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse {path}: {source}")]
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+
+    #[error("{path}:{line}: {message}")]
+    Zone {
+        path: String,
+        line: usize,
+        message: String,
+    },
+
+    #[error("{path}:{line}: {message}")]
+    Hosts {
+        path: String,
+        line: usize,
+        message: String,
+    },
+
+    #[error("{0}")]
+    Invalid(String),
+}
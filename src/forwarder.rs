@@ -1,89 +1,650 @@
-use std::net::UdpSocket;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::dns_header::DnsHeader;
-use crate::dns_question_and_answer::{DnsAnswer, DnsQuestion};
+use crate::cname;
+use crate::dns_header::{DnsFlags, DnsHeader};
+use crate::dns_message::DnsMessage;
+use crate::dns_question_and_answer::{DnsAnswer, DnsQuestion, RecordType};
+use crate::edns::{EdnsOpt, SERVER_UDP_PAYLOAD};
+use crate::error::DnsError;
+use crate::logging::{debug, warning};
+use crate::upstream::UpstreamPool;
 
-/// Parse answers from an upstream DNS response
-/// Returns the answers extracted from the response
-fn parse_answers_from_response(buf: &[u8]) -> Result<Vec<DnsAnswer>, String> {
-    // Parse the header first to get answer count
-    let header = DnsHeader::from_bytes(&buf[0..12])
-        .map_err(|e| format!("Failed to parse response header: {}", e))?;
+/// Most questions of one request that are forwarded at the same time
+const MAX_PARALLEL_QUESTIONS: usize = 8;
 
-    let mut offset = 12; // Start after header
+/// Timing limits for talking to the upstream resolvers
+#[derive(Debug, Clone, Copy)]
+pub struct ForwarderConfig {
+    pub attempt_timeout: Duration, // How long to wait for a reply to each send
+    pub retries: u32,              // Extra rounds over the upstreams after the first one
+    pub deadline: Duration,        // Total budget for forwarding one client request
+}
+
+impl Default for ForwarderConfig {
+    fn default() -> Self {
+        ForwarderConfig {
+            attempt_timeout: Duration::from_secs(2),
+            retries: 2,
+            deadline: Duration::from_secs(5),
+        }
+    }
+}
 
-    // Skip over the question section
-    for _ in 0..header.question_count {
-        let (_, new_offset) = DnsQuestion::from_bytes(buf, offset)?;
-        offset = new_offset;
+/// Outcome and records collected from upstream responses, section by section
+/// The upstream OPT record is hop-by-hop, so it is not kept
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamResponse {
+    pub rcode: u16,           // NXDOMAIN carries the SOA to cache it by in `authorities`
+    pub authentic_data: bool, // AD: upstream validated the records with DNSSEC
+    pub authoritative: bool,  // AA: answered from a zone we serve, never from upstream
+    pub answers: Vec<DnsAnswer>,
+    pub authorities: Vec<DnsAnswer>,
+    pub additionals: Vec<DnsAnswer>,
+}
+
+impl UpstreamResponse {
+    /// Move the records of another response to the end of each section
+    /// The first error RCODE wins, so one failed question is not masked by the others
+    pub fn append(&mut self, mut other: UpstreamResponse) {
+        if self.rcode == 0 {
+            self.rcode = other.rcode;
+        }
+        self.answers.append(&mut other.answers);
+        self.authorities.append(&mut other.authorities);
+        self.additionals.append(&mut other.additionals);
     }
 
-    // Parse the answers
-    let mut answers = Vec::new();
-    for _ in 0..header.answer_count {
-        let (answer, new_offset) = DnsAnswer::from_bytes(buf, offset)?;
-        answers.push(answer);
-        offset = new_offset;
+    /// Continue a CNAME chain with the response for the name it points to
+    /// The outcome is that of the end of the chain (RFC 6604), while AA stays that of
+    /// the first name (RFC 1034 section 4.3.2)
+    pub fn extend_chain(&mut self, mut tail: UpstreamResponse) {
+        self.rcode = tail.rcode;
+        self.authentic_data &= tail.authentic_data;
+        self.answers.append(&mut tail.answers);
+        self.authorities = tail.authorities;
+        self.additionals.append(&mut tail.additionals);
     }
 
-    Ok(answers)
+    /// The reply to `question`, keeping only the answers on its CNAME chain so records for
+    /// unrelated names cannot ride along into the cache; a looping chain is an error
+    pub fn for_question(question: &DnsQuestion, message: DnsMessage) -> Result<Self, DnsError> {
+        let mut response = UpstreamResponse::from(message);
+        let chain = cname::follow(&question.name, question.qtype, &response.answers)
+            .map_err(DnsError::Upstream)?;
+
+        let dropped = response.answers.len() - chain.records.len();
+        if dropped > 0 {
+            debug!(
+                "Dropped {} upstream records off the CNAME chain of {}",
+                dropped, question.name
+            );
+        }
+        response.answers = chain.records;
+        Ok(response)
+    }
+}
+
+impl From<DnsMessage> for UpstreamResponse {
+    fn from(message: DnsMessage) -> Self {
+        let flags = DnsFlags::from_u16(message.header.flags);
+        let additionals = message
+            .additionals
+            .into_iter()
+            .filter(|record| record.rtype != RecordType::OPT.to_u16())
+            .collect();
+
+        UpstreamResponse {
+            rcode: flags.rcode as u16,
+            authentic_data: flags.ad,
+            authoritative: false,
+            answers: message.answers,
+            authorities: message.authorities,
+            additionals,
+        }
+    }
 }
 
 /// Build a DNS query with a single question to send to upstream resolver
-fn build_single_question_query(original_id: u16, question: &DnsQuestion) -> Vec<u8> {
-    let mut query = Vec::new();
+/// AD is set so the upstream reports whether it validated the answer (RFC 6840 section 5.7),
+/// CD passes on the client's wish to skip validation
+pub(crate) fn build_single_question_query(
+    query_id: u16,
+    question: &DnsQuestion,
+    checking_disabled: bool,
+) -> Result<Vec<u8>, DnsError> {
+    let flags = DnsFlags {
+        qr: false,
+        opcode: 0,
+        aa: false,
+        tc: false,
+        rd: true, // Cloudflare 1.1.1.1 would like RD bit to be set
+        ra: false,
+        z: 0,
+        ad: true,
+        cd: checking_disabled,
+        rcode: 0,
+    };
 
     // Build header for a standard query
     let header = DnsHeader {
-        id: original_id,
-        flags: 0x0100, // Cloudflare 1.1.1.1 would like RD bit to be set (using 0x0100 for RD=1)
-        question_count: 1, // Single question
+        id: query_id,
+        flags: flags.to_u16(),
+        question_count: 0, // Counts are filled in from the sections
         answer_count: 0,
         authority_count: 0,
         additional_count: 0,
     };
 
-    // Add header
-    query.extend_from_slice(&header.to_bytes());
+    let mut query = DnsMessage::new(header);
 
     // Add the single question
-    query.extend(question.to_bytes());
+    query.questions.push(question.clone());
+
+    // Advertise a larger UDP buffer so upstream does not truncate at 512 bytes
+    query.additionals.push(EdnsOpt::for_query().to_record());
 
-    query
+    query.to_bytes()
+}
+
+/// Check that a datagram is the reply to our query and not a stray or spoofed packet
+/// It must come from the resolver we asked, carry our ID and the QR bit,
+/// and echo exactly the question we sent (names compare case-insensitively)
+pub(crate) fn validate_response(
+    buf: &[u8],
+    source: SocketAddr,
+    resolver: SocketAddr,
+    query_id: u16,
+    question: &DnsQuestion,
+) -> Result<DnsMessage, String> {
+    if source != resolver {
+        return Err(format!("unexpected source {}", source));
+    }
+
+    let message = DnsMessage::from_bytes(buf).map_err(|e| format!("unparsable reply: {}", e))?;
+
+    if message.header.id != query_id {
+        return Err(format!("wrong ID {:#06x}", message.header.id));
+    }
+
+    if !DnsFlags::from_u16(message.header.flags).qr {
+        return Err("QR bit not set".to_string());
+    }
+
+    let echoed_matches = match message.questions.as_slice() {
+        [echoed] => {
+            echoed.name.eq_ignore_ascii_case(&question.name)
+                && echoed.qtype == question.qtype
+                && echoed.qclass == question.qclass
+        }
+        _ => false,
+    };
+    if !echoed_matches {
+        return Err("question does not match the query".to_string());
+    }
+
+    Ok(message)
+}
+
+/// Wildcard address with a random port in the same address family as `upstream`
+pub(crate) fn local_addr_for(upstream: SocketAddr) -> &'static str {
+    if upstream.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    }
 }
 
-/// Forward questions to upstream resolver and collect answers
-/// Creates a new socket, sends each question individually, and collects all answers
+/// Bind a fresh socket (random source port) of the same address family as `upstream`
+fn bind_for(upstream: SocketAddr) -> Result<UdpSocket, DnsError> {
+    UdpSocket::bind(local_addr_for(upstream))
+        .map_err(DnsError::io("Failed to bind upstream socket"))
+}
+
+/// Send a query once and wait until `attempt_deadline` for the matching reply
+/// Datagrams that fail validation are dropped and we keep listening
+/// Returns None when nothing valid arrived in time
+fn attempt(
+    socket: &UdpSocket,
+    resolver: SocketAddr,
+    query: &[u8],
+    query_id: u16,
+    question: &DnsQuestion,
+    attempt_deadline: Instant,
+) -> Result<Option<DnsMessage>, DnsError> {
+    // Forward to resolver
+    socket
+        .send_to(query, resolver)
+        .map_err(DnsError::io("Failed to send to resolver"))?;
+
+    let mut response_buf = [0u8; SERVER_UDP_PAYLOAD as usize];
+
+    loop {
+        let remaining = attempt_deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+
+        // A zero timeout means "block forever" to the OS, remaining is never zero here
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(DnsError::io("Failed to set upstream timeout"))?;
+
+        // Receive response from upstream resolver
+        match socket.recv_from(&mut response_buf) {
+            Ok((response_size, source)) => {
+                match validate_response(
+                    &response_buf[..response_size],
+                    source,
+                    resolver,
+                    query_id,
+                    question,
+                ) {
+                    Ok(message) => return Ok(Some(message)),
+                    Err(reason) => warning!("Ignoring upstream datagram: {}", reason),
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(DnsError::io("Failed to receive from resolver")(e)),
+        }
+    }
+}
+
+/// Ask the upstreams for one question until one of them answers
+/// Each round sends once to every candidate in turn; there are `retries + 1` rounds
+/// Every upstream gets its own socket, so a late reply to an earlier round still counts
+/// Gives up once the rounds are used up or the deadline has passed
+fn exchange(
+    upstreams: &UpstreamPool,
+    question: &DnsQuestion,
+    checking_disabled: bool,
+    config: &ForwarderConfig,
+    deadline: Instant,
+) -> Result<DnsMessage, DnsError> {
+    // Fresh random ID, so replies cannot be predicted from the client's query
+    let query_id: u16 = rand::random();
+    let query = build_single_question_query(query_id, question, checking_disabled)?;
+
+    let candidates = upstreams.candidates();
+    let mut sockets: Vec<Option<UdpSocket>> = candidates.iter().map(|_| None).collect();
+    let total_attempts = (config.retries + 1) * candidates.len() as u32;
+    let mut attempts = 0;
+
+    'rounds: for _ in 0..=config.retries {
+        for (upstream, socket) in candidates.iter().zip(sockets.iter_mut()) {
+            let now = Instant::now();
+            if now >= deadline {
+                break 'rounds;
+            }
+            attempts += 1;
+
+            let socket = match socket {
+                Some(socket) => socket,
+                None => socket.insert(bind_for(upstream.addr)?),
+            };
+            let attempt_deadline = deadline.min(now + config.attempt_timeout);
+
+            match attempt(
+                socket,
+                upstream.addr,
+                &query,
+                query_id,
+                question,
+                attempt_deadline,
+            ) {
+                Ok(Some(message)) => {
+                    upstream.record_success(now.elapsed());
+                    return Ok(message);
+                }
+                Ok(None) => warning!(
+                    "No answer from resolver {} (attempt {}/{})",
+                    upstream.addr,
+                    attempts,
+                    total_attempts
+                ),
+                Err(e) => warning!(
+                    "Resolver {} failed (attempt {}/{}): {}",
+                    upstream.addr,
+                    attempts,
+                    total_attempts,
+                    e
+                ),
+            }
+            upstream.record_failure();
+        }
+    }
+
+    Err(DnsError::UpstreamTimeout {
+        resolver: upstreams.to_string(),
+        attempts,
+    })
+}
+
+/// Forward questions to the upstream resolvers, returning one result per question
+/// Questions are asked in parallel, each with its own random ID and the client's CD bit,
+/// so one failing question does not hold up or fail the others
+/// All questions share one deadline; sends are retried and spread over the
+/// upstreams per `config` and the pool's strategy
 pub fn forward_to_resolver(
-    resolver_addr: &str,
-    request_id: u16,
+    upstreams: &UpstreamPool,
     questions: &[DnsQuestion],
-) -> Result<Vec<DnsAnswer>, String> {
-    // Create a socket for upstream communication
-    let upstream_socket = UdpSocket::bind("0.0.0.0:0")
-        .map_err(|e| format!("Failed to bind upstream socket: {}", e))?;
+    checking_disabled: bool,
+    config: &ForwarderConfig,
+) -> Vec<Result<UpstreamResponse, DnsError>> {
+    let deadline = Instant::now() + config.deadline;
+    let forward = |question: &DnsQuestion| {
+        exchange(upstreams, question, checking_disabled, config, deadline)
+            .and_then(|message| UpstreamResponse::for_question(question, message))
+    };
+
+    // A lone question needs no extra thread
+    if let [question] = questions {
+        return vec![forward(question)];
+    }
 
-    let mut answers = Vec::new();
+    let forward = &forward;
+    let mut results = Vec::with_capacity(questions.len());
 
     // Public resolvers often like single question, so we split them
-    for question in questions {
-        let single_query = build_single_question_query(request_id, question);
+    for batch in questions.chunks(MAX_PARALLEL_QUESTIONS) {
+        thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|question| scope.spawn(move || forward(question)))
+                .collect();
 
-        // Forward to resolver
-        upstream_socket
-            .send_to(&single_query, resolver_addr)
-            .map_err(|e| format!("Failed to send to resolver: {}", e))?;
+            for handle in handles {
+                results.push(handle.join().unwrap_or_else(|_| {
+                    Err(DnsError::Upstream("Forwarding thread panicked".to_string()))
+                }));
+            }
+        });
+    }
 
-        // Receive response from upstream resolver
-        let mut response_buf = [0u8; 512];
-        let (response_size, _) = upstream_socket
-            .recv_from(&mut response_buf)
-            .map_err(|e| format!("Failed to receive from resolver: {}", e))?;
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::Strategy;
+
+    fn question() -> DnsQuestion {
+        DnsQuestion {
+            name: "example.com".to_string(),
+            qtype: 1,
+            qclass: 1,
+        }
+    }
+
+    fn pool(addrs: &[String]) -> UpstreamPool {
+        UpstreamPool::new(addrs, Strategy::Failover).unwrap()
+    }
+
+    fn forward_one(
+        upstreams: &UpstreamPool,
+        config: &ForwarderConfig,
+    ) -> Result<UpstreamResponse, DnsError> {
+        forward_to_resolver(upstreams, &[question()], false, config).remove(0)
+    }
+
+    fn quick_config() -> ForwarderConfig {
+        ForwarderConfig {
+            attempt_timeout: Duration::from_millis(50),
+            retries: 2,
+            deadline: Duration::from_secs(1),
+        }
+    }
+
+    /// Upstream stub that ignores the first `ignore` queries, then echoes one back as a response
+    fn spawn_upstream(ignore: usize) -> (String, thread::JoinHandle<usize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
 
-        // Parse answers from upstream response
-        let mut parsed_answers = parse_answers_from_response(&response_buf[..response_size])?;
-        answers.append(&mut parsed_answers);
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let mut received = 0;
+            loop {
+                let (size, source) = socket.recv_from(&mut buf).unwrap();
+                received += 1;
+                if received > ignore {
+                    buf[2] |= 0x80; // QR: turn the query into a response
+                    socket.send_to(&buf[..size], source).unwrap();
+                    return received;
+                }
+            }
+        });
+
+        (addr, handle)
     }
 
-    Ok(answers)
+    #[test]
+    fn test_forward_retries_after_lost_packet() {
+        let (addr, upstream) = spawn_upstream(1);
+
+        let response = forward_one(&pool(&[addr]), &quick_config());
+        assert!(response.is_ok());
+        assert_eq!(upstream.join().unwrap(), 2);
+    }
+
+    /// Upstream stub that sends the given bogus replies before the real one
+    fn spawn_spoofed_upstream(
+        spoof: fn(&mut Vec<u8>),
+        from_other_socket: bool,
+    ) -> (String, thread::JoinHandle<()>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, source) = socket.recv_from(&mut buf).unwrap();
+            let mut reply = buf[..size].to_vec();
+            reply[2] |= 0x80; // QR: turn the query into a response
+
+            let mut bogus = reply.clone();
+            spoof(&mut bogus);
+            if from_other_socket {
+                let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+                other.send_to(&bogus, source).unwrap();
+            } else {
+                socket.send_to(&bogus, source).unwrap();
+            }
+
+            socket.send_to(&reply, source).unwrap();
+        });
+
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_forward_ignores_wrong_id() {
+        let (addr, upstream) = spawn_spoofed_upstream(|reply| reply[1] ^= 0xFF, false);
+        assert!(forward_one(&pool(&[addr]), &quick_config()).is_ok());
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn test_forward_ignores_missing_qr() {
+        let (addr, upstream) = spawn_spoofed_upstream(|reply| reply[2] &= 0x7F, false);
+        assert!(forward_one(&pool(&[addr]), &quick_config()).is_ok());
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn test_forward_ignores_wrong_question() {
+        // Flip the first letter of "example"
+        let (addr, upstream) = spawn_spoofed_upstream(|reply| reply[13] = b'x', false);
+        assert!(forward_one(&pool(&[addr]), &quick_config()).is_ok());
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn test_forward_ignores_other_source() {
+        let (addr, upstream) = spawn_spoofed_upstream(|_| {}, true);
+        assert!(forward_one(&pool(&[addr]), &quick_config()).is_ok());
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn test_validate_response_case_insensitive_name() {
+        let resolver: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let mut upper = question();
+        upper.name = "EXAMPLE.com".to_string();
+
+        let mut reply = DnsMessage::new(DnsHeader {
+            id: 7,
+            flags: 0x8180,
+            question_count: 0,
+            answer_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        });
+        reply.questions.push(upper);
+        let bytes = reply.to_bytes().unwrap();
+
+        assert!(validate_response(&bytes, resolver, resolver, 7, &question()).is_ok());
+        assert!(validate_response(&bytes, resolver, resolver, 8, &question()).is_err());
+    }
+
+    #[test]
+    fn test_forward_gives_up_after_retries() {
+        // Bound but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+
+        let started = Instant::now();
+        let error = forward_one(&pool(&[addr]), &quick_config()).unwrap_err();
+
+        assert!(matches!(
+            error,
+            DnsError::UpstreamTimeout { attempts: 3, .. }
+        ));
+        assert_eq!(error.rcode(), crate::dns_message::RCODE_SERVFAIL);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_forward_carries_rcode_and_flags() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        let upstream = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, source) = socket.recv_from(&mut buf).unwrap();
+            let query_flags = DnsFlags::from_u16(u16::from_be_bytes([buf[2], buf[3]]));
+            buf[2] |= 0x80; // QR
+            buf[3] |= 0x03; // NXDOMAIN, AD and CD are already set from the query
+            socket.send_to(&buf[..size], source).unwrap();
+            query_flags
+        });
+
+        let questions = [question()];
+        let mut responses = forward_to_resolver(&pool(&[addr]), &questions, true, &quick_config());
+        let response = responses.remove(0).unwrap();
+        assert_eq!(response.rcode, crate::dns_message::RCODE_NXDOMAIN);
+        assert!(response.authentic_data);
+
+        let query_flags = upstream.join().unwrap();
+        assert!(query_flags.rd && query_flags.ad && query_flags.cd);
+    }
+
+    /// Upstream stub that only answers once it has seen queries for `names` distinct names,
+    /// and never answers `unanswered`
+    fn spawn_gathering_upstream(names: usize, unanswered: &'static str) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let mut queries = Vec::new();
+            while queries.len() < names {
+                let (size, source) = socket.recv_from(&mut buf).unwrap();
+                let query = DnsMessage::from_bytes(&buf[..size]).unwrap();
+                if queries.iter().all(|(seen, _, _): &(DnsMessage, _, _)| {
+                    seen.questions[0].name != query.questions[0].name
+                }) {
+                    queries.push((query, buf[..size].to_vec(), source));
+                }
+            }
+
+            for (query, mut reply, source) in queries {
+                if query.questions[0].name != unanswered {
+                    reply[2] |= 0x80; // QR
+                    socket.send_to(&reply, source).unwrap();
+                }
+            }
+        });
+
+        addr
+    }
+
+    fn questions(names: &[&str]) -> Vec<DnsQuestion> {
+        names
+            .iter()
+            .map(|name| DnsQuestion {
+                name: name.to_string(),
+                ..question()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_forward_asks_questions_in_parallel() {
+        // Asked one after the other, the first question would time out
+        let addr = spawn_gathering_upstream(3, "");
+        let config = ForwarderConfig {
+            retries: 0,
+            ..quick_config()
+        };
+
+        let questions = questions(&["a.example", "b.example", "c.example"]);
+        let results = forward_to_resolver(&pool(&[addr]), &questions, false, &config);
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| result.is_ok()));
+    }
+
+    #[test]
+    fn test_forward_reports_failure_per_question() {
+        let addr = spawn_gathering_upstream(2, "b.example");
+
+        let questions = questions(&["a.example", "b.example"]);
+        let results = forward_to_resolver(&pool(&[addr]), &questions, false, &quick_config());
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(DnsError::UpstreamTimeout { .. })));
+    }
+
+    #[test]
+    fn test_forward_fails_over_to_next_upstream() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap().to_string();
+        let (addr, upstream) = spawn_upstream(0);
+
+        let upstreams = pool(&[silent_addr, addr]);
+        let response = forward_one(&upstreams, &quick_config());
+        assert!(response.is_ok());
+        assert_eq!(upstream.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_forward_respects_deadline() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+
+        let config = ForwarderConfig {
+            attempt_timeout: Duration::from_secs(5),
+            retries: 5,
+            deadline: Duration::from_millis(100),
+        };
+
+        let started = Instant::now();
+        let error = forward_one(&pool(&[addr]), &config).unwrap_err();
+
+        assert!(matches!(
+            error,
+            DnsError::UpstreamTimeout { attempts: 1, .. }
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
 }
@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::dns_question_and_answer::{DnsAnswer, RecordClass, RecordType};
+use crate::error::ConfigError;
+use crate::rdata::{qualify_name, RData};
+
+/// TTL of the records from a hosts file, which has no place to set one
+const HOSTS_TTL: u32 = 300;
+
+/// Read a hosts file (`address name [alias...]` per line, `#` comments) into A and AAAA
+/// records for every name, and a PTR record for every address pointing at its first name
+pub fn load_hosts_file(path: &Path) -> Result<Vec<DnsAnswer>, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.display().to_string(),
+        source,
+    })?;
+    parse_hosts(&text).map_err(|(line, message)| ConfigError::Hosts {
+        path: path.display().to_string(),
+        line,
+        message,
+    })
+}
+
+fn parse_hosts(text: &str) -> Result<Vec<DnsAnswer>, (usize, String)> {
+    let mut records = Vec::new();
+    let mut seen = HashSet::new(); // Names and addresses already listed
+    let mut reversed = HashSet::new(); // Addresses that have their PTR record
+
+    for (index, line) in text.lines().enumerate() {
+        let at = |message: String| (index + 1, message);
+
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(address) = fields.next() else {
+            continue;
+        };
+
+        // Link-local addresses may carry a scope (fe80::1%lo0), which DNS has no use for
+        let address: IpAddr = address
+            .split('%')
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| at(format!("Invalid address {}", address)))?;
+        let names = fields
+            .map(|name| qualify_name(name, ".").map_err(|e| at(e.to_string())))
+            .collect::<Result<Vec<String>, _>>()?;
+        if names.is_empty() {
+            return Err(at(format!("No name for {}", address)));
+        }
+
+        for name in &names {
+            if seen.insert((name.to_ascii_lowercase(), address)) {
+                records.push(address_record(name.clone(), address));
+            }
+        }
+        if reversed.insert(address) {
+            let data = RData::PTR(names[0].clone())
+                .to_bytes()
+                .map_err(|e| at(e.to_string()))?;
+            records.push(DnsAnswer::new(
+                reverse_name(address),
+                RecordType::PTR.to_u16(),
+                RecordClass::IN.to_u16(),
+                HOSTS_TTL,
+                data,
+            ));
+        }
+    }
+
+    Ok(records)
+}
+
+fn address_record(name: String, address: IpAddr) -> DnsAnswer {
+    match address {
+        IpAddr::V4(v4) => DnsAnswer::new_a_record(name, HOSTS_TTL, v4.octets()),
+        IpAddr::V6(v6) => DnsAnswer::new_aaaa_record(name, HOSTS_TTL, v6.octets()),
+    }
+}
+
+/// The name reverse lookups of an address ask for (RFC 1035 section 3.5, RFC 3596
+/// section 2.5), e.g. 4.3.2.1.in-addr.arpa for 1.2.3.4
+pub fn reverse_name(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(v4) => {
+            let octets: Vec<String> = v4.octets().iter().rev().map(u8::to_string).collect();
+            format!("{}.in-addr.arpa", octets.join("."))
+        }
+        IpAddr::V6(v6) => {
+            let nibbles: Vec<String> = v6
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|byte| [byte & 0x0f, byte >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hosts() {
+        let text = "# Internal overrides\n\
+                    127.0.0.1   localhost\n\
+                    10.0.0.5    build.internal build   # CI\n\
+                    10.0.0.6    build.internal\n\
+                    fe80::1%lo0 localhost\n\
+                    \n";
+        let records = parse_hosts(text).unwrap();
+        let summary: Vec<(&str, Option<RecordType>)> = records
+            .iter()
+            .map(|r| (r.name.as_str(), RecordType::from_u16(r.rtype)))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("localhost", Some(RecordType::A)),
+                ("1.0.0.127.in-addr.arpa", Some(RecordType::PTR)),
+                ("build.internal", Some(RecordType::A)),
+                ("build", Some(RecordType::A)),
+                ("5.0.0.10.in-addr.arpa", Some(RecordType::PTR)),
+                ("build.internal", Some(RecordType::A)),
+                ("6.0.0.10.in-addr.arpa", Some(RecordType::PTR)),
+                ("localhost", Some(RecordType::AAAA)),
+                (
+                    "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.e.f.ip6.arpa",
+                    Some(RecordType::PTR)
+                ),
+            ]
+        );
+        assert_eq!(
+            records[4].data().unwrap(),
+            RData::PTR("build.internal".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_lines() {
+        assert_eq!(parse_hosts("10.0.0.1\n").unwrap_err().0, 1);
+        assert_eq!(parse_hosts("# ok\n10.0.0.300 host\n").unwrap_err().0, 2);
+        assert!(parse_hosts("10.0.0.1 bad..name\n").is_err());
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Pending connections the kernel queues before we accept them
+const TCP_BACKLOG: i32 = 128;
+
+/// Create a socket for `addr`
+/// IPv6 sockets only take IPv6 traffic, so `[::]:53` and `0.0.0.0:53` can both be
+/// listened on instead of the second one failing with "address in use"
+fn socket_for(addr: SocketAddr, socket_type: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), socket_type, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    Ok(socket)
+}
+
+/// Bind a UDP socket to `addr`
+fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket_for(addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Bind a TCP listener to `addr`
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = socket_for(addr, Type::STREAM, Protocol::TCP)?;
+    // Restarting must not wait for connections of the previous run to time out
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(TCP_BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Bind UDP and TCP on `addr`
+/// With port 0, TCP listens on the same port the system picked for UDP
+pub fn bind_pair(addr: SocketAddr) -> Result<(UdpSocket, TcpListener), String> {
+    let udp_socket =
+        bind_udp(addr).map_err(|e| format!("Failed to bind UDP to {}: {}", addr, e))?;
+    let udp_addr = udp_socket
+        .local_addr()
+        .map_err(|e| format!("Failed to get UDP address for {}: {}", addr, e))?;
+    let tcp_listener =
+        bind_tcp(udp_addr).map_err(|e| format!("Failed to bind TCP to {}: {}", addr, e))?;
+
+    Ok((udp_socket, tcp_listener))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv4_and_ipv6_wildcards_coexist() {
+        let (udp, _tcp) = bind_pair("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = udp.local_addr().unwrap().port();
+
+        // Hosts without IPv6 cannot run this half
+        let ipv6 = SocketAddr::new("::".parse().unwrap(), port);
+        if let Err(e) = bind_pair(ipv6) {
+            assert!(!e.contains("in use"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_bind_pair_shares_port() {
+        let (udp, tcp) = bind_pair("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert_eq!(udp.local_addr().unwrap(), tcp.local_addr().unwrap());
+    }
+}
@@ -1,4 +1,443 @@
-use crate::dns_question_and_answer::{DnsAnswer, DnsQuestion};
+use std::collections::HashMap;
+
+use crate::cname;
+use crate::dns_message::{RCODE_NXDOMAIN, RCODE_SERVFAIL};
+use crate::dns_question_and_answer::{DnsAnswer, DnsQuestion, RecordClass, RecordType};
+use crate::forwarder::UpstreamResponse;
+use crate::logging::warning;
+use crate::rdata::RData;
+
+/// Records configured on this server, answered without asking any upstream
+/// Names are matched case-insensitively (RFC 4343)
+#[derive(Debug, Default)]
+pub struct LocalRecords {
+    records: HashMap<(String, u16), Vec<DnsAnswer>>, // By lowercased name and type
+    zones: Vec<Zone>,
+}
+
+impl LocalRecords {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a record; records with the same name and type form one answer
+    pub fn add(&mut self, record: DnsAnswer) {
+        self.records
+            .entry((record.name.to_ascii_lowercase(), record.rtype))
+            .or_default()
+            .push(record);
+    }
+
+    /// Records answering a question: those of its type or else a CNAME for the name,
+    /// or None if we have neither
+    pub fn lookup(&self, question: &DnsQuestion) -> Option<Vec<DnsAnswer>> {
+        if question.qclass != RecordClass::IN.to_u16() {
+            return None;
+        }
+        let name = question.name.to_ascii_lowercase();
+        let alias = (name.clone(), RecordType::CNAME.to_u16());
+        self.records
+            .get(&(name, question.qtype))
+            .or_else(|| self.records.get(&alias))
+            .cloned()
+    }
+
+    /// Serve a zone; its origin must not be served already
+    pub fn add_zone(&mut self, zone: Zone) -> Result<(), String> {
+        if self.zones.iter().any(|other| other.origin == zone.origin) {
+            return Err(format!("Zone {} is loaded twice", zone.origin));
+        }
+        self.zones.push(zone);
+        Ok(())
+    }
+
+    pub fn has_zones(&self) -> bool {
+        !self.zones.is_empty()
+    }
+
+    /// The response to a question we can answer ourselves, or None to look elsewhere
+    /// Zones answer every name under their origin, authoritatively; other local records
+    /// only the names and types they hold
+    pub fn answer(&self, question: &DnsQuestion) -> Option<UpstreamResponse> {
+        if question.qclass != RecordClass::IN.to_u16() {
+            return None;
+        }
+
+        let name = question.name.to_ascii_lowercase();
+        let zone = self
+            .zones
+            .iter()
+            .filter(|zone| zone.contains(&name))
+            .max_by_key(|zone| zone.origin.len()); // The most specific zone
+        if let Some(zone) = zone {
+            return zone.answer(&question.name, question.qtype);
+        }
+
+        self.lookup(question).map(|answers| UpstreamResponse {
+            answers,
+            ..Default::default()
+        })
+    }
+
+    /// Answer a question from our own data, following a CNAME chain as far as our data
+    /// goes and appending what each name in it answers
+    /// A loop or an overlong chain is answered with SERVFAIL
+    pub fn resolve(&self, question: &DnsQuestion) -> Option<LocalAnswer> {
+        let mut response = self.answer(question)?;
+
+        loop {
+            let chain = match cname::follow(&question.name, question.qtype, &response.answers) {
+                Ok(chain) => chain,
+                Err(e) => {
+                    warning!("Failed to answer {} locally: {}", question.name, e);
+                    response = UpstreamResponse {
+                        rcode: RCODE_SERVFAIL,
+                        ..Default::default()
+                    };
+                    return Some(LocalAnswer {
+                        response,
+                        tail: None,
+                    });
+                }
+            };
+
+            let tail = match chain.tail {
+                Some(tail) if chain.is_incomplete(&question.name) => DnsQuestion {
+                    name: tail,
+                    ..question.clone()
+                },
+                _ => {
+                    return Some(LocalAnswer {
+                        response,
+                        tail: None,
+                    })
+                }
+            };
+
+            // A name we have nothing for, or that is delegated away, is for the upstreams
+            match self.answer(&tail) {
+                Some(next) if !next.answers.is_empty() => response.extend_chain(next),
+                Some(next) if next.authoritative => {
+                    response.extend_chain(next);
+                    return Some(LocalAnswer {
+                        response,
+                        tail: None,
+                    });
+                }
+                _ => {
+                    return Some(LocalAnswer {
+                        response,
+                        tail: Some(tail),
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// What our own data answers for a question, following CNAMEs through it
+#[derive(Debug)]
+pub struct LocalAnswer {
+    pub response: UpstreamResponse,
+    pub tail: Option<DnsQuestion>, // Where the chain leaves our data, for the upstreams
+}
+
+/// A zone we are authoritative for, loaded from a zone file, as a tree of names
+#[derive(Debug)]
+pub struct Zone {
+    origin: String, // Lowercased, like all names below
+    soa: DnsAnswer,
+    minimum: u32, // SOA minimum, which caps the TTL of negative answers (RFC 2308)
+    apex: Node,
+}
+
+/// One name in a zone tree, with its records and the names one label below it
+/// A node without records is an empty non-terminal: the name exists but owns nothing
+#[derive(Debug, Default)]
+struct Node {
+    records: HashMap<u16, Vec<DnsAnswer>>, // By type
+    children: HashMap<String, Node>,       // By lowercased label
+}
+
+impl Node {
+    fn is_delegation(&self) -> bool {
+        self.records.contains_key(&RecordType::NS.to_u16())
+    }
+
+    /// Records answering a query of `qtype`: those of the type, or else the CNAME
+    fn lookup(&self, qtype: u16) -> Option<&Vec<DnsAnswer>> {
+        self.records
+            .get(&qtype)
+            .or_else(|| self.records.get(&RecordType::CNAME.to_u16()))
+    }
+}
+
+/// Where a name leads in a zone tree
+enum Position<'a> {
+    Exact(&'a Node),     // The name exists, with or without records
+    Delegated(&'a Node), // The name is at or below a cut; the node holding its NS records
+    Missing(&'a Node),   // No such name; the closest encloser, its deepest existing ancestor
+}
+
+impl Zone {
+    /// Check the records of a zone and build its tree
+    /// A zone needs exactly one SOA and at least one NS at its origin, all records at or
+    /// below the origin, and no other data next to a CNAME (RFC 1034 section 3.6.2);
+    /// at and below a delegation only the NS records and their addresses (glue) may be
+    pub fn new(origin: &str, records: Vec<DnsAnswer>) -> Result<Self, String> {
+        let origin = origin.to_ascii_lowercase();
+        let mut apex = Node::default();
+
+        for record in records {
+            let name = record.name.to_ascii_lowercase();
+            let labels = labels_below(&origin, &name)
+                .ok_or_else(|| format!("{} is outside the zone", record.name))?;
+            let node = labels.into_iter().fold(&mut apex, |node, label| {
+                node.children.entry(label.to_string()).or_default()
+            });
+            node.records.entry(record.rtype).or_default().push(record);
+        }
+
+        let (soa, minimum) = match apex
+            .records
+            .get(&RecordType::SOA.to_u16())
+            .map(Vec::as_slice)
+        {
+            Some([record]) => match record.data() {
+                Ok(RData::SOA { minimum, .. }) => (record.clone(), minimum),
+                _ => return Err("Invalid SOA record".to_string()),
+            },
+            Some(_) => return Err("More than one SOA record at the origin".to_string()),
+            None => return Err("No SOA record at the origin".to_string()),
+        };
+        if !apex.is_delegation() {
+            return Err("No NS record at the origin".to_string());
+        }
+        check(&apex, true, false)?;
+
+        Ok(Zone {
+            origin,
+            soa,
+            minimum,
+            apex,
+        })
+    }
+
+    /// Whether a lowercased name is at or below the origin
+    fn contains(&self, name: &str) -> bool {
+        labels_below(&self.origin, name).is_some()
+    }
+
+    /// Walk down the labels of a name below the origin, stopping at the first delegation
+    /// on the way
+    fn find(&self, labels: &[&str]) -> Position<'_> {
+        let mut node = &self.apex;
+        for label in labels {
+            match node.children.get(*label) {
+                Some(child) => node = child,
+                None => return Position::Missing(node),
+            }
+            if node.is_delegation() {
+                return Position::Delegated(node);
+            }
+        }
+        Position::Exact(node)
+    }
+
+    /// A and AAAA records of a lowercased name, including glue below delegations
+    fn addresses(&self, name: &str) -> Vec<DnsAnswer> {
+        let mut node = &self.apex;
+        for label in labels_below(&self.origin, name).unwrap_or_default() {
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return Vec::new(),
+            }
+        }
+
+        [RecordType::A, RecordType::AAAA]
+            .iter()
+            .filter_map(|rtype| node.records.get(&rtype.to_u16()))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Answer for a name in this zone, or None if it is not in it: its records or CNAME,
+    /// records synthesized from a wildcard, a referral to a delegated child zone, or NODATA
+    /// or NXDOMAIN with the SOA in authority
+    fn answer(&self, name: &str, qtype: u16) -> Option<UpstreamResponse> {
+        let lowercase = name.to_ascii_lowercase();
+        let labels = labels_below(&self.origin, &lowercase)?;
+
+        let mut response = ResponseBuilder::new(self);
+        match self.find(&labels) {
+            Position::Exact(node) => match node.lookup(qtype) {
+                Some(records) => response.answer(records),
+                None => response.negative(0),
+            },
+            Position::Delegated(cut) => response.referral(cut),
+            // A wildcard only stands in for names below the closest encloser that do not
+            // exist at all (RFC 4592 section 3.3.1)
+            Position::Missing(encloser) => match encloser.children.get("*") {
+                Some(wildcard) => match wildcard.lookup(qtype) {
+                    Some(records) => response.answer(&synthesize(records, name)),
+                    None => response.negative(0),
+                },
+                None => response.negative(RCODE_NXDOMAIN),
+            },
+        }
+        Some(response.build())
+    }
+}
+
+/// Copies of wildcard records with the query name as their owner (RFC 4592 section 3.3.1)
+fn synthesize(records: &[DnsAnswer], name: &str) -> Vec<DnsAnswer> {
+    records
+        .iter()
+        .map(|record| DnsAnswer {
+            name: name.to_string(),
+            ..record.clone()
+        })
+        .collect()
+}
+
+/// The labels of a lowercased name below `origin`, from the top down, or None if the
+/// name is not at or below it
+fn labels_below<'a>(origin: &str, name: &'a str) -> Option<Vec<&'a str>> {
+    let prefix = if name == origin {
+        ""
+    } else if origin == "." {
+        name
+    } else {
+        name.strip_suffix(origin)?.strip_suffix('.')?
+    };
+    Some(
+        prefix
+            .split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .collect(),
+    )
+}
+
+/// Check the records of a node and everything below it
+/// `below_cut` is set under a delegation, where only glue addresses may be
+fn check(node: &Node, is_apex: bool, below_cut: bool) -> Result<(), String> {
+    let at_cut = !is_apex && node.is_delegation();
+
+    for (&rtype, records) in &node.records {
+        let name = &records[0].name;
+        let type_name = RecordType::from_u16(rtype)
+            .map(|rtype| format!("{:?}", rtype))
+            .unwrap_or_else(|| format!("TYPE{}", rtype));
+
+        if rtype == RecordType::SOA.to_u16() && !is_apex {
+            return Err(format!("{} has an SOA record below the origin", name));
+        }
+        if rtype == RecordType::CNAME.to_u16() {
+            if records.len() > 1 {
+                return Err(format!("{} has more than one CNAME record", name));
+            }
+            if node.records.len() > 1 {
+                return Err(format!("{} has a CNAME record next to other data", name));
+            }
+        }
+
+        let address = rtype == RecordType::A.to_u16() || rtype == RecordType::AAAA.to_u16();
+        let delegation = at_cut && rtype == RecordType::NS.to_u16();
+        if (at_cut || below_cut) && !address && !delegation {
+            return Err(format!(
+                "{} {} is hidden by a delegation (only NS records and glue may be there)",
+                name, type_name
+            ));
+        }
+    }
+
+    // A wildcard delegation would refer clients for names that do not exist (RFC 4592
+    // section 4.2), so it is not served
+    if let Some(wildcard) = node.children.get("*").filter(|child| child.is_delegation()) {
+        let name = &wildcard.records[&RecordType::NS.to_u16()][0].name;
+        return Err(format!("{} cannot have NS records", name));
+    }
+
+    node.children
+        .values()
+        .try_for_each(|child| check(child, false, below_cut || at_cut))
+}
+
+/// Assembles a response from a zone, section by section
+/// The addresses of the name servers and mail exchangers it mentions go in the additional
+/// section when the zone has them, which for a referral is the glue
+struct ResponseBuilder<'a> {
+    zone: &'a Zone,
+    response: UpstreamResponse,
+}
+
+impl<'a> ResponseBuilder<'a> {
+    fn new(zone: &'a Zone) -> Self {
+        ResponseBuilder {
+            zone,
+            response: UpstreamResponse {
+                authoritative: true,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn answer(&mut self, records: &[DnsAnswer]) {
+        self.response.answers.extend_from_slice(records);
+        self.add_addresses(records);
+    }
+
+    /// Point the client at the name servers of a delegated child zone
+    /// We are not authoritative for the child, so AA is cleared (RFC 1034 section 4.3.2)
+    fn referral(&mut self, cut: &Node) {
+        let servers = &cut.records[&RecordType::NS.to_u16()];
+        self.response.authoritative = false;
+        self.response.authorities.extend_from_slice(servers);
+        self.add_addresses(servers);
+    }
+
+    /// NODATA (RCODE 0) or NXDOMAIN, with the SOA the client may cache it by
+    fn negative(&mut self, rcode: u16) {
+        self.response.rcode = rcode;
+        let mut soa = self.zone.soa.clone();
+        soa.ttl = soa.ttl.min(self.zone.minimum);
+        self.response.authorities.push(soa);
+    }
+
+    fn add_addresses(&mut self, records: &[DnsAnswer]) {
+        for record in records {
+            let target = match record.data() {
+                Ok(RData::NS(target))
+                | Ok(RData::MX {
+                    exchange: target, ..
+                }) => target,
+                _ => continue,
+            };
+
+            for address in self.zone.addresses(&target.to_ascii_lowercase()) {
+                let known = self
+                    .response
+                    .answers
+                    .iter()
+                    .chain(&self.response.additionals)
+                    .any(|other| {
+                        other.name == address.name
+                            && other.rtype == address.rtype
+                            && other.rdata == address.rdata
+                    });
+                if !known {
+                    self.response.additionals.push(address);
+                }
+            }
+        }
+    }
+
+    fn build(self) -> UpstreamResponse {
+        self.response
+    }
+}
 
 /// Create response answers based on the questions
 /// Takes a reference to questions, returns owned answer structures
@@ -16,3 +455,209 @@ pub fn create_response_answers(questions: &[DnsQuestion]) -> Vec<DnsAnswer> {
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone_file::parse_zone;
+
+    fn question(name: &str, qtype: u16) -> DnsQuestion {
+        DnsQuestion {
+            name: name.to_string(),
+            qtype,
+            qclass: 1,
+        }
+    }
+
+    #[test]
+    fn test_lookup_by_name_and_type() {
+        let mut local = LocalRecords::new();
+        local.add(DnsAnswer::new_a_record(
+            "Router.lan".to_string(),
+            300,
+            [192, 168, 1, 1],
+        ));
+        local.add(DnsAnswer::new_a_record(
+            "router.lan".to_string(),
+            300,
+            [192, 168, 1, 2],
+        ));
+
+        assert_eq!(local.lookup(&question("ROUTER.lan", 1)).unwrap().len(), 2);
+        assert!(local.lookup(&question("router.lan", 28)).is_none());
+        assert!(local.lookup(&question("other.lan", 1)).is_none());
+    }
+
+    const ZONE: &str = "$TTL 3600
+@    SOA ns1 hostmaster 1 7200 900 1209600 300
+     NS  ns1
+ns1  A   192.0.2.53
+www  A   192.0.2.80
+ftp  CNAME www
+";
+
+    fn zone(text: &str) -> Result<Zone, String> {
+        Zone::new("example.com", parse_zone(text, "example.com").unwrap())
+    }
+
+    #[test]
+    fn test_zone_answers() {
+        let mut local = LocalRecords::new();
+        local.add_zone(zone(ZONE).unwrap()).unwrap();
+
+        let found = local.answer(&question("WWW.example.com", 1)).unwrap();
+        assert!(found.authoritative);
+        assert_eq!(found.rcode, 0);
+        assert_eq!(found.answers[0].rdata, vec![192, 0, 2, 80]);
+
+        let alias = local.answer(&question("ftp.example.com", 1)).unwrap();
+        assert_eq!(alias.answers[0].rtype, RecordType::CNAME.to_u16());
+
+        // NODATA and NXDOMAIN carry the SOA, with its TTL capped by the SOA minimum
+        let nodata = local.answer(&question("www.example.com", 28)).unwrap();
+        assert_eq!((nodata.rcode, nodata.answers.len()), (0, 0));
+        assert_eq!(nodata.authorities[0].ttl, 300);
+
+        let nxdomain = local.answer(&question("nope.example.com", 1)).unwrap();
+        assert_eq!(nxdomain.rcode, RCODE_NXDOMAIN);
+        assert_eq!(nxdomain.authorities[0].rtype, RecordType::SOA.to_u16());
+
+        assert!(local.answer(&question("example.org", 1)).is_none());
+        assert!(local.answer(&question("badexample.com", 1)).is_none());
+        assert!(local.add_zone(zone(ZONE).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_zone_validation() {
+        assert!(zone(ZONE).is_ok());
+        assert!(zone("$TTL 60\n@ NS ns1\n").is_err()); // No SOA
+        assert!(zone("$TTL 60\n@ SOA ns1 hm 1 2 3 4 5\n").is_err()); // No NS
+        assert!(zone(&format!("{}www.example.org. A 192.0.2.1\n", ZONE)).is_err());
+        assert!(zone(&format!("{}ftp TXT \"alias\"\n", ZONE)).is_err());
+        assert!(zone(&format!("{}sub SOA ns1 hm 1 2 3 4 5\n", ZONE)).is_err());
+    }
+
+    #[test]
+    fn test_zone_delegations_and_empty_non_terminals() {
+        let text = format!(
+            "{}@ MX 10 mail\nmail A 192.0.2.25\na.b.c A 192.0.2.9\n\
+             sub NS ns.sub\nsub NS ns1\nns.sub A 192.0.2.99\n",
+            ZONE
+        );
+        let mut local = LocalRecords::new();
+        local.add_zone(zone(&text).unwrap()).unwrap();
+
+        // c and b.c own nothing but have names below them
+        let empty = local.answer(&question("c.example.com", 1)).unwrap();
+        assert_eq!((empty.rcode, empty.answers.len()), (0, 0));
+        assert_eq!(empty.authorities[0].rtype, RecordType::SOA.to_u16());
+        let missing = local.answer(&question("x.c.example.com", 1)).unwrap();
+        assert_eq!(missing.rcode, RCODE_NXDOMAIN);
+
+        // Below the cut we only refer, with the glue we have
+        let referral = local.answer(&question("host.sub.example.com", 1)).unwrap();
+        assert!(!referral.authoritative);
+        assert!(referral.answers.is_empty());
+        assert_eq!(referral.authorities.len(), 2);
+        let glue: Vec<&str> = referral
+            .additionals
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect();
+        assert_eq!(glue, vec!["ns.sub.example.com", "ns1.example.com"]);
+
+        let mail = local.answer(&question("example.com", 15)).unwrap();
+        assert!(mail.authoritative);
+        assert_eq!(mail.additionals[0].rdata, vec![192, 0, 2, 25]);
+    }
+
+    #[test]
+    fn test_zone_rejects_data_below_a_delegation() {
+        let cut = format!("{}sub NS ns.sub\nns.sub A 192.0.2.99\n", ZONE);
+        assert!(zone(&cut).is_ok());
+        assert!(zone(&format!("{}sub TXT \"hidden\"\n", cut)).is_err());
+        assert!(zone(&format!("{}www.sub MX 10 mail\n", cut)).is_err());
+    }
+
+    #[test]
+    fn test_zone_wildcards() {
+        let text = format!(
+            "{}*.wild A 192.0.2.7\nhost.wild TXT \"here\"\na.sub.wild A 192.0.2.8\n\
+             *.alias CNAME www\n",
+            ZONE
+        );
+        let mut local = LocalRecords::new();
+        local.add_zone(zone(&text).unwrap()).unwrap();
+
+        // Names that do not exist below the closest encloser take the wildcard's records
+        for name in ["x.wild.example.com", "Y.z.wild.example.com"] {
+            let synthesized = local.answer(&question(name, 1)).unwrap();
+            assert!(synthesized.authoritative);
+            assert_eq!(synthesized.answers[0].name, name);
+            assert_eq!(synthesized.answers[0].rdata, vec![192, 0, 2, 7]);
+        }
+        let alias = local.answer(&question("x.alias.example.com", 1)).unwrap();
+        assert_eq!(alias.answers[0].rtype, RecordType::CNAME.to_u16());
+
+        // The wildcard exists, but not for AAAA
+        let nodata = local.answer(&question("x.wild.example.com", 28)).unwrap();
+        assert_eq!((nodata.rcode, nodata.answers.len()), (0, 0));
+
+        // Names that exist, even as empty non-terminals, are not synthesized
+        let existing = local.answer(&question("host.wild.example.com", 1)).unwrap();
+        assert_eq!((existing.rcode, existing.answers.len()), (0, 0));
+        let empty = local.answer(&question("sub.wild.example.com", 1)).unwrap();
+        assert!(empty.answers.is_empty());
+
+        // sub.wild is the closest encloser here, and has no wildcard
+        let missing = local
+            .answer(&question("b.sub.wild.example.com", 1))
+            .unwrap();
+        assert_eq!(missing.rcode, RCODE_NXDOMAIN);
+
+        assert!(zone(&format!("{}* NS ns1\n", ZONE)).is_err());
+    }
+
+    #[test]
+    fn test_resolve_follows_cname_chains() {
+        let text = format!(
+            "{}alias CNAME ftp\nloop1 CNAME loop2\nloop2 CNAME loop1\n\
+             dangling CNAME nope\nout CNAME www.example.org.\n",
+            ZONE
+        );
+        let mut local = LocalRecords::new();
+        local.add_zone(zone(&text).unwrap()).unwrap();
+
+        // alias -> ftp -> www, all in the zone
+        let chased = local.resolve(&question("alias.example.com", 1)).unwrap();
+        let names: Vec<&str> = chased
+            .response
+            .answers
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["alias.example.com", "ftp.example.com", "www.example.com"]
+        );
+        assert!(chased.response.authoritative);
+        assert!(chased.tail.is_none());
+
+        let looped = local.resolve(&question("loop1.example.com", 1)).unwrap();
+        assert_eq!(looped.response.rcode, RCODE_SERVFAIL);
+
+        // The end of the chain decides the RCODE
+        let dangling = local.resolve(&question("dangling.example.com", 1)).unwrap();
+        assert_eq!(dangling.response.rcode, RCODE_NXDOMAIN);
+        assert_eq!(dangling.response.answers.len(), 1);
+        assert_eq!(
+            dangling.response.authorities[0].rtype,
+            RecordType::SOA.to_u16()
+        );
+
+        // Leaving our data, the rest is up to the upstreams
+        let out = local.resolve(&question("out.example.com", 28)).unwrap();
+        assert_eq!(out.response.answers.len(), 1);
+        assert_eq!(out.tail.unwrap().name, "www.example.org");
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// How much the server logs, from least to most
+/// Errors and warnings go to stderr, the rest to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug, // Adds a line for every request received
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Set the most verbose level that is still logged
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages at `level` are logged
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Error) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Warn) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Debug) {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {debug, error, info, warning};
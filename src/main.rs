@@ -1,19 +1,175 @@
+mod async_forwarder;
+mod async_server;
+mod cache;
+mod cname;
+mod config;
 mod dns_header;
 mod dns_message;
 mod dns_question_and_answer;
+mod edns;
+mod error;
 mod forwarder;
+mod hosts_file;
+mod listener;
 mod local;
+mod logging;
+mod message_writer;
+mod rdata;
+mod reload;
+mod resolver;
 mod server;
+mod tcp;
+mod upstream;
+mod zone_file;
 
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use async_server::AsyncDnsServer;
 use clap::Parser;
+use config::{Config, ZoneConfig};
+use error::ConfigError;
+use futures_util::future::join_all;
+use logging::{error, info, LogLevel};
+use reload::{Load, Reloader};
+use resolver::{Resolver, SharedResolver};
 use server::DnsServer;
+use upstream::Strategy;
 
+/// Flags left out keep the value from the configuration file, or its default
 #[derive(Parser, Debug)]
 #[command(name = "dns-server")]
 struct Args {
-    /// Upstream DNS resolver address (e.g., 8.8.8.8:53)
+    /// Configuration file (TOML) to read before applying the flags below
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Check the configuration and exit, with a non-zero status if it is invalid
+    #[arg(long)]
+    check_config: bool,
+
+    /// Reload when the configuration file changes, not only on SIGHUP
+    #[arg(long, requires = "config")]
+    watch_config: bool,
+
+    /// Address to serve on over UDP and TCP (default 127.0.0.1:2053), repeat for several
+    #[arg(long)]
+    listen: Vec<SocketAddr>,
+
+    /// Upstream DNS resolver address (e.g., 8.8.8.8:53), repeat for several
     #[arg(long)]
-    resolver: Option<String>,
+    resolver: Vec<String>,
+
+    /// Order in which upstream resolvers are tried [default: failover]
+    #[arg(long, value_enum)]
+    upstream_strategy: Option<Strategy>,
+
+    /// How long to wait for each upstream reply before resending, in milliseconds [default: 2000]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    upstream_timeout_ms: Option<u64>,
+
+    /// How many more times to go through the upstream resolvers when none answers [default: 2]
+    #[arg(long)]
+    upstream_retries: Option<u32>,
+
+    /// Total time allowed for forwarding one request, in milliseconds [default: 5000]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    upstream_deadline_ms: Option<u64>,
+
+    /// Maximum number of cached upstream responses, 0 disables the cache [default: 1024]
+    #[arg(long)]
+    cache_size: Option<usize>,
+
+    /// Serve with the tokio-based async server instead of the worker pool
+    #[arg(long = "async")]
+    use_async: bool,
+
+    /// Number of worker threads answering UDP requests (runtime threads with --async) [default: 4]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    workers: Option<u64>,
+
+    /// Number of UDP requests allowed to wait for a worker before new ones are shed [default: 256]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    queue_size: Option<u64>,
+
+    /// Zone to answer authoritatively, as ORIGIN=FILE (e.g., example.com=example.zone),
+    /// repeat for several
+    #[arg(long, value_parser = parse_zone_arg)]
+    zone: Vec<ZoneConfig>,
+
+    /// Hosts-format file (like /etc/hosts) whose names are answered locally, with PTR
+    /// records for their addresses; repeat for several
+    #[arg(long)]
+    hosts_file: Vec<PathBuf>,
+
+    /// How much to log [default: info]
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+}
+
+impl Args {
+    /// Flags that were given win over the configuration file
+    fn apply(&self, config: &mut Config) {
+        if !self.listen.is_empty() {
+            config.listen = self.listen.clone();
+        }
+        if !self.resolver.is_empty() {
+            config.upstream.resolvers = self.resolver.clone();
+        }
+        if let Some(strategy) = self.upstream_strategy {
+            config.upstream.strategy = strategy;
+        }
+        if let Some(timeout_ms) = self.upstream_timeout_ms {
+            config.upstream.timeout_ms = timeout_ms;
+        }
+        if let Some(retries) = self.upstream_retries {
+            config.upstream.retries = retries;
+        }
+        if let Some(deadline_ms) = self.upstream_deadline_ms {
+            config.upstream.deadline_ms = deadline_ms;
+        }
+        if let Some(cache_size) = self.cache_size {
+            config.cache.size = cache_size;
+        }
+        if self.use_async {
+            config.server.use_async = true;
+        }
+        if let Some(workers) = self.workers {
+            config.server.workers = workers as usize;
+        }
+        if let Some(queue_size) = self.queue_size {
+            config.server.queue_size = queue_size as usize;
+        }
+        if let Some(level) = self.log_level {
+            config.logging.level = level;
+        }
+        config.zones.extend(self.zone.iter().cloned());
+        config.hosts_files.extend(self.hosts_file.iter().cloned());
+    }
+}
+
+fn parse_zone_arg(arg: &str) -> Result<ZoneConfig, String> {
+    let (origin, file) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected ORIGIN=FILE, got {}", arg))?;
+    Ok(ZoneConfig {
+        origin: origin.to_string(),
+        file: PathBuf::from(file),
+    })
+}
+
+/// Read the configuration file if there is one, apply the flags and build the resolver
+fn configure(args: &Args) -> Result<(Config, Resolver), ConfigError> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    args.apply(&mut config);
+
+    let resolver = config.resolver()?;
+    Ok((config, resolver))
 }
 
 fn main() {
@@ -21,12 +177,113 @@ fn main() {
 
     let args = Args::parse();
 
-    if let Some(ref addr) = args.resolver {
-        println!("Using resolver: {}", addr);
+    let (config, resolver) = match configure(&args) {
+        Ok(configured) => configured,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if args.check_config {
+        println!("Configuration OK");
+        return;
+    }
+
+    logging::set_level(config.logging.level);
+
+    for addr in &config.upstream.resolvers {
+        info!("Using resolver: {}", addr);
+    }
+    if !config.records.is_empty() {
+        info!("Serving {} local records", config.records.len());
+    }
+    for zone in &config.zones {
+        info!("Serving zone {} from {}", zone.origin, zone.file.display());
+    }
+    for path in &config.hosts_files {
+        info!("Serving names from {}", path.display());
+    }
+
+    let resolver = Arc::new(SharedResolver::new(resolver));
+    let (listen, worker_config) = (config.listen.clone(), config.worker_config());
+    let use_async = config.server.use_async;
+    start_reloading(args, config, resolver.clone());
+
+    if use_async {
+        run_async(resolver, &listen, worker_config.workers);
+        return;
     }
 
     let server =
-        DnsServer::new("127.0.0.1:2053", args.resolver).expect("Failed to create DNS server");
+        DnsServer::new(&listen, resolver, worker_config).expect("Failed to create DNS server");
 
+    for addr in &listen {
+        info!("Listening on {}", addr);
+    }
     server.run();
 }
+
+/// Reload on SIGHUP, and on changes to the configuration file or the zone and hosts
+/// files it started with (not their `$INCLUDE`s) with --watch-config
+/// Reloading goes through the same flags and checks as startup
+fn start_reloading(args: Args, config: Config, resolver: Arc<SharedResolver>) {
+    let watched: Vec<PathBuf> = match &args.config {
+        Some(path) if args.watch_config => std::iter::once(path.clone())
+            .chain(config.zones.iter().map(|zone| zone.file.clone()))
+            .chain(config.hosts_files.iter().cloned())
+            .collect(),
+        _ => Vec::new(),
+    };
+    let load: Load = Box::new(move || configure(&args));
+    let reloader = Arc::new(Mutex::new(Reloader::new(load, config, resolver)));
+
+    #[cfg(unix)]
+    if let Err(e) = reload::reload_on_sighup(reloader.clone()) {
+        error!("Failed to listen for SIGHUP, reloading is disabled: {}", e);
+    }
+
+    if !watched.is_empty() {
+        for path in &watched {
+            info!("Watching {} for changes", path.display());
+        }
+        reload::reload_on_change(reloader, watched);
+    }
+}
+
+/// Run the async server on a tokio runtime until Ctrl-C, one per listen address
+fn run_async(resolver: Arc<SharedResolver>, listen: &[SocketAddr], workers: usize) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .enable_all()
+        .build()
+        .expect("Failed to start async runtime");
+
+    runtime.block_on(async {
+        let (stop, stopped) = tokio::sync::watch::channel(());
+
+        let mut servers = Vec::new();
+        for &addr in listen {
+            let server = AsyncDnsServer::bind(addr, resolver.clone())
+                .await
+                .expect("Failed to create DNS server");
+            info!("Listening on {}", addr);
+
+            let mut stopped = stopped.clone();
+            servers.push(server.run(async move {
+                let _ = stopped.changed().await;
+            }));
+        }
+
+        tokio::spawn(async move {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                error!("Failed to listen for Ctrl-C: {}", e);
+                std::future::pending::<()>().await;
+            }
+            info!("Shutting down");
+            let _ = stop.send(());
+        });
+
+        join_all(servers).await;
+    });
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use crate::dns_question_and_answer::{encode_domain_name, encode_label};
+use crate::error::DnsError;
+
+/// Largest offset a compression pointer can refer to (14 bits)
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Incremental writer for a DNS message
+/// Remembers where every name suffix was written so later occurrences
+/// can be replaced by a two-byte compression pointer (RFC 1035 section 4.1.4)
+pub struct MessageWriter {
+    buf: Vec<u8>,
+    names: HashMap<String, u16>, // Name suffix -> offset where it starts
+    compress: bool,
+}
+
+impl MessageWriter {
+    /// Create a writer that compresses names
+    pub fn new() -> Self {
+        MessageWriter {
+            buf: Vec::new(),
+            names: HashMap::new(),
+            compress: true,
+        }
+    }
+
+    /// Create a writer that always writes names out in full
+    /// Used for RDATA we store, which must not point into any particular message
+    pub fn without_compression() -> Self {
+        MessageWriter {
+            compress: false,
+            ..Self::new()
+        }
+    }
+
+    /// Number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Drop everything written after `len`, forgetting names that lived there
+    /// Used to roll back a record that turned out not to fit
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+        self.names.retain(|_, offset| (*offset as usize) < len);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend(&value.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend(&value.to_be_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+    }
+
+    /// Overwrite a big-endian u16 written earlier (e.g. RDLENGTH once RDATA is known)
+    pub fn patch_u16(&mut self, offset: usize, value: u16) {
+        self.buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Write a domain name, pointing at an earlier copy of its longest known suffix
+    /// Example: after "example.com", "www.example.com" becomes [3]www + pointer
+    pub fn write_name(&mut self, name: &str) -> Result<(), DnsError> {
+        // Validates label and name lengths before anything is written
+        let encoded = encode_domain_name(name)?;
+
+        if !self.compress {
+            self.write_bytes(&encoded);
+            return Ok(());
+        }
+
+        let labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+
+        for index in 0..labels.len() {
+            let suffix = labels[index..].join(".");
+
+            if let Some(&offset) = self.names.get(&suffix) {
+                self.write_u16(0xC000 | offset);
+                return Ok(());
+            }
+
+            if self.buf.len() <= MAX_POINTER_OFFSET {
+                self.names.insert(suffix, self.buf.len() as u16);
+            }
+
+            self.write_bytes(&encode_label(labels[index])?);
+        }
+
+        // Null terminator
+        self.write_u8(0);
+
+        Ok(())
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_question_and_answer::parse_domain_name;
+
+    #[test]
+    fn test_write_name_without_compression() {
+        let mut writer = MessageWriter::without_compression();
+        writer.write_name("example.com").unwrap();
+        writer.write_name("example.com").unwrap();
+
+        let mut expected = encode_domain_name("example.com").unwrap();
+        expected.extend(encode_domain_name("example.com").unwrap());
+        assert_eq!(writer.into_bytes(), expected);
+    }
+
+    #[test]
+    fn test_write_name_compresses_repeats_and_suffixes() {
+        let mut writer = MessageWriter::new();
+        writer.write_name("example.com").unwrap();
+        let second = writer.len();
+        writer.write_name("example.com").unwrap();
+        let third = writer.len();
+        writer.write_name("www.example.com").unwrap();
+        let fourth = writer.len();
+        writer.write_name("mail.example.org").unwrap();
+
+        let bytes = writer.into_bytes();
+        assert_eq!(&bytes[second..third], &[0xC0, 0x00]);
+        assert_eq!(&bytes[third..fourth], &[3, b'w', b'w', b'w', 0xC0, 0x00]);
+
+        assert_eq!(
+            parse_domain_name(&bytes, second).unwrap(),
+            ("example.com".to_string(), third)
+        );
+        assert_eq!(
+            parse_domain_name(&bytes, third).unwrap(),
+            ("www.example.com".to_string(), fourth)
+        );
+        assert_eq!(
+            parse_domain_name(&bytes, fourth).unwrap(),
+            ("mail.example.org".to_string(), bytes.len())
+        );
+    }
+
+    #[test]
+    fn test_root_name() {
+        let mut writer = MessageWriter::new();
+        writer.write_name(".").unwrap();
+        writer.write_name(".").unwrap();
+        assert_eq!(writer.into_bytes(), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_write_name_rejects_long_label() {
+        let mut writer = MessageWriter::new();
+        let label = "a".repeat(64);
+        assert!(matches!(
+            writer.write_name(&label),
+            Err(DnsError::LabelTooLong(_))
+        ));
+        assert_eq!(writer.len(), 0);
+    }
+
+    #[test]
+    fn test_truncate_forgets_names() {
+        let mut writer = MessageWriter::new();
+        writer.write_name("example.com").unwrap();
+        let mark = writer.len();
+        writer.write_name("example.org").unwrap();
+        writer.truncate(mark);
+
+        // example.org was rolled back, so it must be written in full again
+        writer.write_name("example.org").unwrap();
+        let bytes = writer.into_bytes();
+        assert_eq!(
+            &bytes[mark..],
+            &encode_domain_name("example.org").unwrap()[..]
+        );
+    }
+}
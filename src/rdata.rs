@@ -0,0 +1,553 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::dns_question_and_answer::{encode_domain_name, parse_domain_name, RecordType};
+use crate::error::DnsError;
+use crate::message_writer::MessageWriter;
+
+/// Decoded resource data for the record types we understand
+/// Anything else is kept as raw bytes in `Unknown`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum RData {
+    A(Ipv4Addr),
+    NS(String),
+    CNAME(String),
+    SOA {
+        mname: String, // Primary name server
+        rname: String, // Responsible mailbox, encoded as a domain name
+        serial: u32,   // Zone version
+        refresh: u32,  // Seconds between secondary refreshes
+        retry: u32,    // Seconds before retrying a failed refresh
+        expire: u32,   // Seconds until a secondary stops answering
+        minimum: u32,  // Negative caching TTL (RFC 2308)
+    },
+    PTR(String),
+    MX {
+        preference: u16, // Lower is preferred
+        exchange: String,
+    },
+    TXT(Vec<Vec<u8>>), // One or more character-strings
+    AAAA(Ipv6Addr),
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    /// Parse RDATA of type `rtype` found at `bytes[offset..offset + length]`
+    /// `bytes` must be the whole message so that compressed names can be resolved
+    pub fn from_bytes(
+        bytes: &[u8],
+        offset: usize,
+        length: usize,
+        rtype: u16,
+    ) -> Result<Self, DnsError> {
+        let end = offset + length;
+        if end > bytes.len() {
+            return Err(DnsError::Truncated("RDATA"));
+        }
+        let rdata = &bytes[offset..end];
+
+        let data = match RecordType::from_u16(rtype) {
+            Some(RecordType::A) => {
+                let octets: [u8; 4] = rdata.try_into().map_err(|_| {
+                    DnsError::BadRdata(format!("A record must be 4 bytes, got {}", length))
+                })?;
+                RData::A(Ipv4Addr::from(octets))
+            }
+            Some(RecordType::AAAA) => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| {
+                    DnsError::BadRdata(format!("AAAA record must be 16 bytes, got {}", length))
+                })?;
+                RData::AAAA(Ipv6Addr::from(octets))
+            }
+            Some(RecordType::NS) => RData::NS(parse_single_name(bytes, offset, end)?),
+            Some(RecordType::CNAME) => RData::CNAME(parse_single_name(bytes, offset, end)?),
+            Some(RecordType::PTR) => RData::PTR(parse_single_name(bytes, offset, end)?),
+            Some(RecordType::MX) => {
+                if length < 2 {
+                    return Err(DnsError::BadRdata("MX record too short".to_string()));
+                }
+                let preference = u16::from_be_bytes([rdata[0], rdata[1]]);
+                let exchange = parse_single_name(bytes, offset + 2, end)?;
+                RData::MX {
+                    preference,
+                    exchange,
+                }
+            }
+            Some(RecordType::SOA) => {
+                let (mname, name_end) = parse_name_within(bytes, offset, end)?;
+                let (rname, name_end) = parse_name_within(bytes, name_end, end)?;
+
+                if end - name_end != 20 {
+                    return Err(DnsError::BadRdata(
+                        "SOA record has wrong length".to_string(),
+                    ));
+                }
+                let field = |index: usize| {
+                    let start = name_end + index * 4;
+                    u32::from_be_bytes([
+                        bytes[start],
+                        bytes[start + 1],
+                        bytes[start + 2],
+                        bytes[start + 3],
+                    ])
+                };
+
+                RData::SOA {
+                    mname,
+                    rname,
+                    serial: field(0),
+                    refresh: field(1),
+                    retry: field(2),
+                    expire: field(3),
+                    minimum: field(4),
+                }
+            }
+            Some(RecordType::TXT) => {
+                let mut strings = Vec::new();
+                let mut position = 0;
+                while position < rdata.len() {
+                    let string_length = rdata[position] as usize;
+                    position += 1;
+                    if position + string_length > rdata.len() {
+                        return Err(DnsError::BadRdata(
+                            "TXT string extends beyond RDATA".to_string(),
+                        ));
+                    }
+                    strings.push(rdata[position..position + string_length].to_vec());
+                    position += string_length;
+                }
+                RData::TXT(strings)
+            }
+            Some(RecordType::OPT) | None => RData::Unknown(rdata.to_vec()),
+        };
+
+        Ok(data)
+    }
+
+    /// Parse RDATA of type `rtype` from presentation format, as written in zone files
+    /// `fields` are the whitespace-separated fields with quotes removed; relative names
+    /// are completed with `origin`
+    pub fn from_presentation(
+        rtype: RecordType,
+        fields: &[&str],
+        origin: &str,
+    ) -> Result<Self, DnsError> {
+        let expect = |count: usize| {
+            if fields.len() == count {
+                Ok(())
+            } else {
+                Err(DnsError::BadRdata(format!(
+                    "{:?} record needs {} field(s), got {}",
+                    rtype,
+                    count,
+                    fields.len()
+                )))
+            }
+        };
+        let number = |field: &str| {
+            field
+                .parse::<u32>()
+                .map_err(|_| DnsError::BadRdata(format!("Invalid number: {}", field)))
+        };
+
+        let data = match rtype {
+            RecordType::A => {
+                expect(1)?;
+                RData::A(fields[0].parse().map_err(|_| {
+                    DnsError::BadRdata(format!("Invalid IPv4 address: {}", fields[0]))
+                })?)
+            }
+            RecordType::AAAA => {
+                expect(1)?;
+                RData::AAAA(fields[0].parse().map_err(|_| {
+                    DnsError::BadRdata(format!("Invalid IPv6 address: {}", fields[0]))
+                })?)
+            }
+            RecordType::NS => {
+                expect(1)?;
+                RData::NS(qualify_name(fields[0], origin)?)
+            }
+            RecordType::CNAME => {
+                expect(1)?;
+                RData::CNAME(qualify_name(fields[0], origin)?)
+            }
+            RecordType::PTR => {
+                expect(1)?;
+                RData::PTR(qualify_name(fields[0], origin)?)
+            }
+            RecordType::MX => {
+                expect(2)?;
+                RData::MX {
+                    preference: fields[0].parse().map_err(|_| {
+                        DnsError::BadRdata(format!("Invalid MX preference: {}", fields[0]))
+                    })?,
+                    exchange: qualify_name(fields[1], origin)?,
+                }
+            }
+            RecordType::SOA => {
+                expect(7)?;
+                RData::SOA {
+                    mname: qualify_name(fields[0], origin)?,
+                    rname: qualify_name(fields[1], origin)?,
+                    serial: number(fields[2])?,
+                    refresh: number(fields[3])?,
+                    retry: number(fields[4])?,
+                    expire: number(fields[5])?,
+                    minimum: number(fields[6])?,
+                }
+            }
+            RecordType::TXT => {
+                if fields.is_empty() {
+                    return Err(DnsError::BadRdata("TXT record needs text".to_string()));
+                }
+                RData::TXT(
+                    fields
+                        .iter()
+                        .map(|field| field.as_bytes().to_vec())
+                        .collect(),
+                )
+            }
+            RecordType::OPT => {
+                return Err(DnsError::BadRdata(
+                    "OPT records cannot be written down".to_string(),
+                ))
+            }
+        };
+
+        // Catch what only shows when encoding, like TXT strings over 255 bytes
+        data.to_bytes()?;
+        Ok(data)
+    }
+
+    /// Encode to wire format with all names written out in full
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DnsError> {
+        let mut writer = MessageWriter::without_compression();
+        self.write_to(&mut writer)?;
+        Ok(writer.into_bytes())
+    }
+
+    /// Append the wire format to a message
+    /// Names in NS, CNAME, PTR, MX and SOA may be compressed (RFC 3597 section 4)
+    pub fn write_to(&self, writer: &mut MessageWriter) -> Result<(), DnsError> {
+        match self {
+            RData::A(ip) => writer.write_bytes(&ip.octets()),
+            RData::AAAA(ip) => writer.write_bytes(&ip.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => writer.write_name(name)?,
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                writer.write_u16(*preference);
+                writer.write_name(exchange)?;
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                writer.write_name(mname)?;
+                writer.write_name(rname)?;
+                for value in [serial, refresh, retry, expire, minimum] {
+                    writer.write_u32(*value);
+                }
+            }
+            RData::TXT(strings) => {
+                for string in strings {
+                    let length = u8::try_from(string.len()).map_err(|_| {
+                        DnsError::BadRdata("TXT string longer than 255 bytes".to_string())
+                    })?;
+                    writer.write_u8(length);
+                    writer.write_bytes(string);
+                }
+            }
+            RData::Unknown(raw) => writer.write_bytes(raw),
+        }
+
+        Ok(())
+    }
+
+    /// Record type this data belongs to, or None for `Unknown`
+    /// Meant for tooling that synthesizes records; the server itself does not need it yet
+    #[allow(dead_code)]
+    pub fn record_type(&self) -> Option<RecordType> {
+        match self {
+            RData::A(_) => Some(RecordType::A),
+            RData::NS(_) => Some(RecordType::NS),
+            RData::CNAME(_) => Some(RecordType::CNAME),
+            RData::SOA { .. } => Some(RecordType::SOA),
+            RData::PTR(_) => Some(RecordType::PTR),
+            RData::MX { .. } => Some(RecordType::MX),
+            RData::TXT(_) => Some(RecordType::TXT),
+            RData::AAAA(_) => Some(RecordType::AAAA),
+            RData::Unknown(_) => None,
+        }
+    }
+}
+
+/// Presentation format, as dig would print it
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(ip) => write!(f, "{}", ip),
+            RData::AAAA(ip) => write!(f, "{}", ip),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => {
+                write!(f, "{}", absolute(name))
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, absolute(exchange)),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                absolute(mname),
+                absolute(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            RData::TXT(strings) => {
+                let quoted: Vec<String> = strings
+                    .iter()
+                    .map(|string| format!("\"{}\"", String::from_utf8_lossy(string)))
+                    .collect();
+                write!(f, "{}", quoted.join(" "))
+            }
+            // Generic encoding for unknown types (RFC 3597)
+            RData::Unknown(raw) => {
+                write!(f, "\\# {}", raw.len())?;
+                if !raw.is_empty() {
+                    write!(f, " ")?;
+                    for byte in raw {
+                        write!(f, "{:02x}", byte)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Bring a name from presentation format into the form used everywhere else:
+/// no trailing dot, "@" standing for `origin`, relative names completed with `origin`
+pub fn qualify_name(name: &str, origin: &str) -> Result<String, DnsError> {
+    let qualified = if name == "@" {
+        origin.to_string()
+    } else if name == "." {
+        name.to_string()
+    } else if let Some(absolute) = name.strip_suffix('.') {
+        absolute.to_string()
+    } else if origin == "." {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    };
+
+    if qualified != "." && qualified.split('.').any(str::is_empty) {
+        return Err(DnsError::BadRdata(format!("Empty label in name: {}", name)));
+    }
+    encode_domain_name(&qualified)?;
+
+    Ok(qualified)
+}
+
+/// Split presentation-format RDATA into fields on whitespace
+/// Double quotes group a field that contains spaces (TXT strings) and are removed
+pub fn split_fields(text: &str) -> Result<Vec<String>, DnsError> {
+    let mut fields = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut field = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => field.extend(chars.next()),
+                    Some(c) => field.push(c),
+                    None => {
+                        return Err(DnsError::BadRdata(format!("Unterminated quote: {}", text)))
+                    }
+                }
+            }
+            fields.push(field);
+        } else {
+            let mut field = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                field.push(c);
+            }
+            fields.push(field);
+        }
+    }
+
+    Ok(fields)
+}
+
+/// Write a domain name with its trailing dot
+fn absolute(name: &str) -> String {
+    if name == "." {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+/// Parse a name that must end inside the RDATA bounds
+fn parse_name_within(bytes: &[u8], offset: usize, end: usize) -> Result<(String, usize), DnsError> {
+    let (name, name_end) = parse_domain_name(bytes, offset)?;
+    if name_end > end {
+        return Err(DnsError::BadRdata(
+            "Domain name extends beyond RDATA".to_string(),
+        ));
+    }
+    Ok((name, name_end))
+}
+
+/// Parse a name that must fill the RDATA exactly (NS, CNAME, PTR, MX exchange)
+fn parse_single_name(bytes: &[u8], offset: usize, end: usize) -> Result<String, DnsError> {
+    let (name, name_end) = parse_name_within(bytes, offset, end)?;
+    if name_end != end {
+        return Err(DnsError::BadRdata(
+            "Trailing bytes after domain name".to_string(),
+        ));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_question_and_answer::encode_domain_name;
+
+    fn roundtrip(data: RData) {
+        let rtype = data.record_type().unwrap().to_u16();
+        let bytes = data.to_bytes().unwrap();
+        let parsed = RData::from_bytes(&bytes, 0, bytes.len(), rtype).unwrap();
+        assert_eq!(parsed, data);
+    }
+
+    #[test]
+    fn test_rdata_roundtrip() {
+        roundtrip(RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        roundtrip(RData::AAAA("2001:db8::1".parse().unwrap()));
+        roundtrip(RData::NS("ns1.example.com".to_string()));
+        roundtrip(RData::CNAME("www.example.com".to_string()));
+        roundtrip(RData::PTR("host.example.com".to_string()));
+        roundtrip(RData::MX {
+            preference: 10,
+            exchange: "mail.example.com".to_string(),
+        });
+        roundtrip(RData::TXT(vec![b"v=spf1 -all".to_vec(), Vec::new()]));
+        roundtrip(RData::SOA {
+            mname: "ns1.example.com".to_string(),
+            rname: "hostmaster.example.com".to_string(),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        });
+    }
+
+    #[test]
+    fn test_rdata_resolves_compressed_names() {
+        // "example.com" at offset 0, then MX RDATA: preference 5, "mail" + pointer to offset 0
+        let mut bytes = encode_domain_name("example.com").unwrap();
+        let rdata_offset = bytes.len();
+        bytes.extend([0, 5, 4, b'm', b'a', b'i', b'l', 0xC0, 0x00]);
+
+        let parsed = RData::from_bytes(&bytes, rdata_offset, 9, RecordType::MX.to_u16()).unwrap();
+        assert_eq!(
+            parsed,
+            RData::MX {
+                preference: 5,
+                exchange: "mail.example.com".to_string(),
+            }
+        );
+
+        // Re-encoding writes the name out in full
+        assert_eq!(parsed.to_bytes().unwrap().len(), 2 + 18);
+    }
+
+    #[test]
+    fn test_rdata_rejects_bad_lengths() {
+        assert!(RData::from_bytes(&[1, 2, 3], 0, 3, RecordType::A.to_u16()).is_err());
+        assert!(RData::from_bytes(&[0; 4], 0, 4, RecordType::AAAA.to_u16()).is_err());
+
+        // Name followed by junk inside a CNAME
+        let mut bytes = encode_domain_name("example.com").unwrap();
+        bytes.push(0xFF);
+        assert!(RData::from_bytes(&bytes, 0, bytes.len(), RecordType::CNAME.to_u16()).is_err());
+    }
+
+    #[test]
+    fn test_unknown_type_kept_raw() {
+        let parsed = RData::from_bytes(&[1, 2, 3], 0, 3, 99).unwrap();
+        assert_eq!(parsed, RData::Unknown(vec![1, 2, 3]));
+        assert_eq!(parsed.to_string(), "\\# 3 010203");
+    }
+
+    fn presentation(rtype: RecordType, text: &str, origin: &str) -> Result<RData, DnsError> {
+        let fields = split_fields(text)?;
+        let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+        RData::from_presentation(rtype, &fields, origin)
+    }
+
+    #[test]
+    fn test_from_presentation() {
+        assert_eq!(
+            presentation(RecordType::MX, "10 mail", "example.com").unwrap(),
+            RData::MX {
+                preference: 10,
+                exchange: "mail.example.com".to_string(),
+            }
+        );
+        assert_eq!(
+            presentation(RecordType::CNAME, "www.example.org.", "example.com").unwrap(),
+            RData::CNAME("www.example.org".to_string())
+        );
+        assert_eq!(
+            presentation(RecordType::TXT, r#""v=spf1 -all" "say \"hi\"""#, ".").unwrap(),
+            RData::TXT(vec![b"v=spf1 -all".to_vec(), b"say \"hi\"".to_vec()])
+        );
+
+        assert!(presentation(RecordType::A, "192.0.2.300", ".").is_err());
+        assert!(presentation(RecordType::MX, "mail.example.com", ".").is_err());
+        assert!(presentation(RecordType::NS, "ns..example.com", ".").is_err());
+        assert!(presentation(RecordType::TXT, "\"open", ".").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let mx = RData::MX {
+            preference: 10,
+            exchange: "mail.example.com".to_string(),
+        };
+        assert_eq!(mx.to_string(), "10 mail.example.com.");
+
+        let null_mx = RData::MX {
+            preference: 0,
+            exchange: ".".to_string(),
+        };
+        assert_eq!(null_mx.to_string(), "0 .");
+        assert_eq!(RData::A(Ipv4Addr::new(8, 8, 8, 8)).to_string(), "8.8.8.8");
+    }
+}
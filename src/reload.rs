@@ -0,0 +1,173 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+use crate::error::ConfigError;
+use crate::logging::{self, error, info};
+use crate::resolver::{Resolver, SharedResolver};
+
+/// How often watched files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Builds the configuration and resolver from scratch, the same way as at startup
+pub type Load = Box<dyn Fn() -> Result<(Config, Resolver), ConfigError> + Send>;
+
+/// Reloads the configuration on request and swaps the result in
+pub struct Reloader {
+    load: Load,
+    config: Config, // The configuration currently in effect
+    resolver: Arc<SharedResolver>,
+}
+
+impl Reloader {
+    pub fn new(load: Load, config: Config, resolver: Arc<SharedResolver>) -> Self {
+        Reloader {
+            load,
+            config,
+            resolver,
+        }
+    }
+
+    /// Load the configuration again and swap the new resolver in, logging what changed
+    /// A configuration that fails to load is logged and the running one kept
+    pub fn reload(&mut self) {
+        let (config, mut resolver) = match (self.load)() {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                error!("Reload failed, keeping the running configuration: {}", e);
+                return;
+            }
+        };
+
+        // Cached answers stay valid as long as they come from the same upstreams
+        if config.cache == self.config.cache
+            && config.upstream.resolvers == self.config.upstream.resolvers
+        {
+            resolver.keep_cache_of(&self.resolver.current());
+        }
+
+        logging::set_level(config.logging.level);
+        self.resolver.replace(resolver);
+
+        let changes = self.config.changes(&config);
+        if changes.is_empty() {
+            info!("Configuration reloaded, nothing changed");
+        } else {
+            info!("Configuration reloaded:");
+            for change in changes {
+                info!("  {}", change);
+            }
+        }
+        self.config = config;
+    }
+}
+
+/// Reload from SIGHUP and the watcher never overlaps; a panic during one reload
+/// leaves the previous configuration in place, so the next one can go ahead
+fn lock(reloader: &Mutex<Reloader>) -> MutexGuard<'_, Reloader> {
+    reloader.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reload whenever the process receives SIGHUP
+#[cfg(unix)]
+pub fn reload_on_sighup(reloader: Arc<Mutex<Reloader>>) -> std::io::Result<()> {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("Received SIGHUP, reloading configuration");
+            lock(&reloader).reload();
+        }
+    });
+    Ok(())
+}
+
+/// Reload whenever one of `paths` changes on disk, checking every `WATCH_INTERVAL`
+pub fn reload_on_change(reloader: Arc<Mutex<Reloader>>, paths: Vec<PathBuf>) {
+    thread::spawn(move || {
+        let mut seen = stamps(&paths);
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+
+            let current = stamps(&paths);
+            if current != seen {
+                seen = current;
+                info!("Configuration files changed, reloading");
+                lock(&reloader).reload();
+            }
+        }
+    });
+}
+
+/// Modification time and size of each file, None for one that cannot be read
+/// Size is included because quick successive writes can share a modification time
+fn stamps(paths: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    paths
+        .iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads whatever configuration the test last put in `next`
+    fn reloader(next: Arc<Mutex<Option<Config>>>) -> Reloader {
+        let load: Load = Box::new(move || {
+            let config = next
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| ConfigError::Invalid("Broken configuration".to_string()))?;
+            let resolver = config.resolver()?;
+            Ok((config, resolver))
+        });
+
+        let config = Config::default();
+        let resolver = Arc::new(SharedResolver::new(config.resolver().unwrap()));
+        Reloader::new(load, config, resolver)
+    }
+
+    #[test]
+    fn test_reload_swaps_resolver() {
+        let next = Arc::new(Mutex::new(None));
+        let mut reloader = reloader(next.clone());
+        let before = reloader.resolver.current();
+
+        // A broken configuration leaves everything as it was
+        reloader.reload();
+        assert!(Arc::ptr_eq(&before, &reloader.resolver.current()));
+
+        let mut config = Config::default();
+        config.upstream.retries = 5;
+        *next.lock().unwrap() = Some(config);
+
+        reloader.reload();
+        assert!(!Arc::ptr_eq(&before, &reloader.resolver.current()));
+        assert_eq!(reloader.config.upstream.retries, 5);
+    }
+
+    #[test]
+    fn test_stamps_track_changes() {
+        let path = std::env::temp_dir().join(format!("dns-reload-{}.toml", std::process::id()));
+        let paths = vec![path.clone()];
+        assert_eq!(stamps(&paths), vec![None]);
+
+        fs::write(&path, "listen = []").unwrap();
+        let written = stamps(&paths);
+        assert!(written[0].is_some());
+
+        fs::write(&path, "listen = [\"127.0.0.1:53\"]").unwrap();
+        assert_ne!(stamps(&paths), written);
+        fs::remove_file(&path).unwrap();
+    }
+}
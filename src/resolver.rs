@@ -0,0 +1,447 @@
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::async_forwarder::forward_to_resolver_async;
+use crate::cache::Cache;
+use crate::dns_header::DnsFlags;
+use crate::dns_message::{
+    create_response_header, set_rcode, DnsMessage, RCODE_REFUSED, RCODE_SERVFAIL,
+};
+use crate::dns_question_and_answer::DnsQuestion;
+use crate::error::DnsError;
+use crate::forwarder::{forward_to_resolver, ForwarderConfig, UpstreamResponse};
+use crate::local::{create_response_answers, LocalAnswer, LocalRecords};
+use crate::logging::warning;
+use crate::upstream::UpstreamPool;
+
+/// Where answers come from: local records, or upstream resolvers (through the cache)
+/// Shared by the blocking and the async server, which differ only in how they wait
+pub struct Resolver {
+    local: LocalRecords,
+    upstreams: Option<UpstreamPool>,
+    forwarder_config: ForwarderConfig,
+    cache: Option<Arc<Cache>>,
+}
+
+impl Resolver {
+    /// Answer from `local` records first, then optionally from upstream resolvers,
+    /// with `forwarder_config` limiting how long we wait for them
+    /// and `cache` holding their answers
+    pub fn new(
+        local: LocalRecords,
+        upstreams: Option<UpstreamPool>,
+        forwarder_config: ForwarderConfig,
+        cache: Option<Cache>,
+    ) -> Self {
+        Resolver {
+            local,
+            upstreams,
+            forwarder_config,
+            cache: cache.map(Arc::new),
+        }
+    }
+
+    /// Take over the cache of the resolver this one replaces, so a reload that does
+    /// not touch the upstreams does not throw their answers away
+    pub fn keep_cache_of(&mut self, previous: &Resolver) {
+        self.cache = previous.cache.clone();
+    }
+
+    /// Collect the records answering a request, blocking while upstreams are asked
+    pub fn resolve(&self, request: &DnsMessage) -> Result<DnsMessage, DnsError> {
+        let Some(upstreams) = &self.upstreams else {
+            return Ok(self.local_response(request));
+        };
+
+        let checking_disabled = DnsFlags::from_u16(request.header.flags).cd;
+        let lookup = Lookup::new(
+            &self.local,
+            self.cache_for(checking_disabled),
+            &request.questions,
+        );
+
+        let results = if lookup.missing.is_empty() {
+            Vec::new()
+        } else {
+            forward_to_resolver(
+                upstreams,
+                &lookup.missing_questions(),
+                checking_disabled,
+                &self.forwarder_config,
+            )
+        };
+
+        let upstream = lookup.complete(results)?;
+        Ok(build_response(request, upstream, true))
+    }
+
+    /// Collect the records answering a request without blocking the runtime
+    /// Dropping the future cancels any upstream queries still in flight
+    pub async fn resolve_async(&self, request: &DnsMessage) -> Result<DnsMessage, DnsError> {
+        let Some(upstreams) = &self.upstreams else {
+            return Ok(self.local_response(request));
+        };
+
+        let checking_disabled = DnsFlags::from_u16(request.header.flags).cd;
+        let lookup = Lookup::new(
+            &self.local,
+            self.cache_for(checking_disabled),
+            &request.questions,
+        );
+
+        let results = if lookup.missing.is_empty() {
+            Vec::new()
+        } else {
+            forward_to_resolver_async(
+                upstreams,
+                &lookup.missing_questions(),
+                checking_disabled,
+                &self.forwarder_config,
+            )
+            .await
+        };
+
+        let upstream = lookup.complete(results)?;
+        Ok(build_response(request, upstream, true))
+    }
+
+    /// With CD set the answers may not have been validated, so the cache is bypassed
+    fn cache_for(&self, checking_disabled: bool) -> Option<&Cache> {
+        self.cache.as_deref().filter(|_| !checking_disabled)
+    }
+
+    /// No resolver configured - answer from local records; names outside our zones are
+    /// refused, and without any zones get a dummy answer
+    fn local_response(&self, request: &DnsMessage) -> DnsMessage {
+        let mut merged = UpstreamResponse {
+            authoritative: !request.questions.is_empty(),
+            ..Default::default()
+        };
+        for question in &request.questions {
+            let part = match self.local.resolve(question) {
+                Some(local) => local.response,
+                None if self.local.has_zones() => UpstreamResponse {
+                    rcode: RCODE_REFUSED,
+                    ..Default::default()
+                },
+                None => UpstreamResponse {
+                    answers: create_response_answers(std::slice::from_ref(question)),
+                    ..Default::default()
+                },
+            };
+            merged.authoritative &= part.authoritative;
+            merged.append(part);
+        }
+        build_response(request, merged, false)
+    }
+}
+
+/// The resolver currently answering requests, replaced as a whole on reload
+/// Requests hold on to the resolver they started with, so a reload never
+/// mixes old and new settings within one request
+pub struct SharedResolver {
+    current: RwLock<Arc<Resolver>>,
+}
+
+impl SharedResolver {
+    pub fn new(resolver: Resolver) -> Self {
+        SharedResolver {
+            current: RwLock::new(Arc::new(resolver)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Resolver> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Swap in a new resolver; requests already running finish with the old one
+    pub fn replace(&self, resolver: Resolver) {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(resolver);
+    }
+}
+
+/// Build the response to a request from what local records and the upstreams told us
+/// RA is only set when there are upstreams to recurse to
+fn build_response(
+    request: &DnsMessage,
+    upstream: UpstreamResponse,
+    recursion_available: bool,
+) -> DnsMessage {
+    let mut response = DnsMessage::new(create_response_header(&request.header));
+    response.questions = request.questions.clone();
+
+    let mut flags = DnsFlags::from_u16(response.header.flags);
+    flags.ra = recursion_available;
+    flags.aa = upstream.authoritative;
+    flags.ad = upstream.authentic_data;
+    response.header.flags = flags.to_u16();
+    set_rcode(&mut response.header, upstream.rcode);
+
+    response.answers = upstream.answers;
+    response.authorities = upstream.authorities;
+    response.additionals = upstream.additionals;
+    response
+}
+
+/// Answers for the questions of one request, filled from local records, then the cache
+/// and then the upstreams; parts follow question order
+/// A CNAME chain that leaves our own data is completed the same way, by asking about
+/// the name it ends at
+struct Lookup<'a> {
+    cache: Option<&'a Cache>,
+    parts: Vec<Option<UpstreamResponse>>,
+    asked: Vec<DnsQuestion>, // Per part: its question, or the end of its local chain
+    missing: Vec<usize>,     // Parts neither local records nor the cache could complete
+}
+
+impl<'a> Lookup<'a> {
+    fn new(local: &LocalRecords, cache: Option<&'a Cache>, questions: &[DnsQuestion]) -> Self {
+        let mut lookup = Lookup {
+            cache,
+            parts: Vec::with_capacity(questions.len()),
+            asked: Vec::with_capacity(questions.len()),
+            missing: Vec::new(),
+        };
+
+        for (index, question) in questions.iter().enumerate() {
+            let (part, asked) = match local.resolve(question) {
+                Some(LocalAnswer { response, tail }) => (Some(response), tail),
+                None => (None, Some(question.clone())),
+            };
+            lookup.parts.push(part);
+
+            let Some(asked) = asked else {
+                lookup.asked.push(question.clone());
+                continue;
+            };
+            match cache.and_then(|cache| cache.get(&asked)) {
+                Some(cached) => lookup.settle(index, cached),
+                None => lookup.missing.push(index),
+            }
+            lookup.asked.push(asked);
+        }
+
+        lookup
+    }
+
+    fn missing_questions(&self) -> Vec<DnsQuestion> {
+        self.missing
+            .iter()
+            .map(|&index| self.asked[index].clone())
+            .collect()
+    }
+
+    /// Put what was found for a part in place, after the local start of its chain if any
+    fn settle(&mut self, index: usize, response: UpstreamResponse) {
+        match &mut self.parts[index] {
+            Some(start) => start.extend_chain(response),
+            part => *part = Some(response),
+        }
+    }
+
+    /// Fill in the upstream results for the missing questions and merge everything
+    /// A question the upstreams could not answer gets SERVFAIL, the rest still count;
+    /// only when nothing could be answered does the whole request fail
+    fn complete(
+        mut self,
+        results: Vec<Result<UpstreamResponse, DnsError>>,
+    ) -> Result<UpstreamResponse, DnsError> {
+        let mut first_error = None;
+        let mut failures = 0;
+
+        for (index, result) in std::mem::take(&mut self.missing).into_iter().zip(results) {
+            let question = &self.asked[index];
+            let part = match result {
+                Ok(fresh) => {
+                    if let Some(cache) = self.cache {
+                        cache.insert(question, &fresh);
+                    }
+                    fresh
+                }
+                Err(e) => {
+                    warning!("Failed to resolve {}: {}", question.name, e);
+                    failures += 1;
+                    first_error.get_or_insert(e);
+                    UpstreamResponse {
+                        rcode: RCODE_SERVFAIL,
+                        ..Default::default()
+                    }
+                }
+            };
+            self.settle(index, part);
+        }
+
+        if let Some(e) = first_error.filter(|_| failures == self.parts.len()) {
+            return Err(e);
+        }
+
+        // The whole response is only authentic, or authoritative, if every part of it is
+        let mut merged = UpstreamResponse {
+            authentic_data: self.parts.iter().flatten().all(|part| part.authentic_data),
+            authoritative: self.parts.iter().flatten().all(|part| part.authoritative),
+            ..Default::default()
+        };
+        for part in self.parts.into_iter().flatten() {
+            merged.append(part);
+        }
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_header::DnsHeader;
+    use crate::dns_question_and_answer::{encode_domain_name, DnsAnswer, RecordType};
+    use crate::local::Zone;
+
+    fn questions() -> Vec<DnsQuestion> {
+        ["a.example", "b.example"]
+            .iter()
+            .map(|name| DnsQuestion {
+                name: name.to_string(),
+                qtype: 1,
+                qclass: 1,
+            })
+            .collect()
+    }
+
+    fn answer(name: &str) -> UpstreamResponse {
+        UpstreamResponse {
+            answers: vec![DnsAnswer::new_a_record(
+                name.to_string(),
+                300,
+                [192, 0, 2, 1],
+            )],
+            authentic_data: true,
+            ..Default::default()
+        }
+    }
+
+    fn timeout() -> DnsError {
+        DnsError::UpstreamTimeout {
+            resolver: "127.0.0.1:53".to_string(),
+            attempts: 3,
+        }
+    }
+
+    #[test]
+    fn test_lookup_uses_cache_first() {
+        let cache = Cache::new(10);
+        let questions = questions();
+        cache.insert(&questions[1], &answer("b.example"));
+
+        let lookup = Lookup::new(&LocalRecords::new(), Some(&cache), &questions);
+        assert_eq!(lookup.missing, vec![0]);
+
+        let merged = lookup.complete(vec![Ok(answer("a.example"))]).unwrap();
+        let names: Vec<&str> = merged.answers.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["a.example", "b.example"]);
+        assert!(merged.authentic_data);
+
+        // The fresh answer went into the cache
+        assert!(cache.get(&questions[0]).is_some());
+    }
+
+    #[test]
+    fn test_lookup_prefers_local_records() {
+        let mut local = LocalRecords::new();
+        local.add(DnsAnswer::new_a_record(
+            "a.example".to_string(),
+            60,
+            [10, 0, 0, 1],
+        ));
+        let questions = questions();
+
+        let lookup = Lookup::new(&local, None, &questions);
+        assert_eq!(lookup.missing, vec![1]);
+
+        let merged = lookup.complete(vec![Ok(answer("b.example"))]).unwrap();
+        assert_eq!(merged.answers[0].rdata, vec![10, 0, 0, 1]);
+        assert_eq!(merged.answers.len(), 2);
+    }
+
+    #[test]
+    fn test_lookup_partial_failure() {
+        let questions = questions();
+        let lookup = Lookup::new(&LocalRecords::new(), None, &questions);
+
+        let merged = lookup
+            .complete(vec![Ok(answer("a.example")), Err(timeout())])
+            .unwrap();
+        assert_eq!(merged.rcode, RCODE_SERVFAIL);
+        assert_eq!(merged.answers.len(), 1);
+        assert!(!merged.authentic_data);
+    }
+
+    #[test]
+    fn test_lookup_total_failure() {
+        let questions = questions();
+        let lookup = Lookup::new(&LocalRecords::new(), None, &questions);
+
+        let result = lookup.complete(vec![Err(timeout()), Err(timeout())]);
+        assert!(matches!(result, Err(DnsError::UpstreamTimeout { .. })));
+    }
+
+    #[test]
+    fn test_refuses_names_outside_the_zones() {
+        let records = crate::zone_file::parse_zone(
+            "$TTL 60\n@ SOA ns hm 1 2 3 4 5\n@ NS ns\nns A 192.0.2.1\n",
+            "example.com",
+        )
+        .unwrap();
+        let mut local = LocalRecords::new();
+        local
+            .add_zone(Zone::new("example.com", records).unwrap())
+            .unwrap();
+        let resolver = Resolver::new(local, None, ForwarderConfig::default(), None);
+
+        let mut request = DnsMessage::new(DnsHeader {
+            id: 1,
+            flags: 0x0100,
+            question_count: 2,
+            answer_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        });
+        request.questions = questions();
+        request.questions[0].name = "ns.example.com".to_string();
+
+        let response = resolver.resolve(&request).unwrap();
+        let flags = DnsFlags::from_u16(response.header.flags);
+        assert_eq!(flags.rcode as u16, RCODE_REFUSED);
+        assert!(!flags.aa && !flags.ra);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_lookup_completes_local_chains_upstream() {
+        let mut local = LocalRecords::new();
+        local.add(DnsAnswer::new(
+            "a.example".to_string(),
+            RecordType::CNAME.to_u16(),
+            1,
+            60,
+            encode_domain_name("cdn.example.net").unwrap(),
+        ));
+        let questions = questions();
+
+        let lookup = Lookup::new(&local, None, &questions);
+        assert_eq!(lookup.missing, vec![0, 1]);
+        let asked: Vec<String> = lookup
+            .missing_questions()
+            .into_iter()
+            .map(|q| q.name)
+            .collect();
+        assert_eq!(asked, vec!["cdn.example.net", "b.example"]);
+
+        let merged = lookup
+            .complete(vec![Ok(answer("cdn.example.net")), Ok(answer("b.example"))])
+            .unwrap();
+        let names: Vec<&str> = merged.answers.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["a.example", "cdn.example.net", "b.example"]);
+        assert!(!merged.authentic_data);
+    }
+}
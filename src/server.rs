@@ -1,72 +1,411 @@
-use std::net::UdpSocket;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Scope};
+use std::time::Duration;
 
-use crate::dns_message::{build_response, create_response_header, parse_request};
-use crate::forwarder::forward_to_resolver;
-use crate::local::create_response_answers;
+use crate::dns_header::{DnsFlags, DnsHeader};
+use crate::dns_message::{
+    build_error_response, build_response, DnsMessage, MAX_TCP_PAYLOAD, MAX_UDP_PAYLOAD,
+    RCODE_NOTIMP, RCODE_SERVFAIL,
+};
+use crate::edns::{EdnsOpt, EDNS_VERSION, RCODE_BADVERS, SERVER_UDP_PAYLOAD};
+use crate::error::DnsError;
+use crate::listener::bind_pair;
+use crate::logging::{debug, error, warning};
+use crate::resolver::SharedResolver;
+use crate::tcp;
+
+/// How long an idle TCP connection is kept open waiting for the next query
+pub(crate) const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Transport a request arrived on, which decides how large the response may be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// A UDP request waiting for a worker: the datagram, who sent it and the socket
+/// it arrived on, which the response goes out through
+type UdpJob<'a> = (Vec<u8>, SocketAddr, &'a UdpSocket);
+
+/// Sizing of the worker pool that answers UDP requests
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerConfig {
+    pub workers: usize,    // Requests answered at the same time
+    pub queue_size: usize, // Requests allowed to wait for a free worker
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            workers: 4,
+            queue_size: 256,
+        }
+    }
+}
+
+/// One address the server listens on, over both UDP and TCP
+struct Listener {
+    udp_socket: UdpSocket,
+    tcp_listener: TcpListener,
+}
 
 /// DNS Server that handles incoming DNS requests
 pub struct DnsServer {
-    socket: UdpSocket,
-    resolver: Option<String>,
+    listeners: Vec<Listener>,
+    resolver: Arc<SharedResolver>,
+    worker_config: WorkerConfig,
 }
 
 impl DnsServer {
-    /// Create a new DNS server bound to the given address
-    /// Optionally configure an upstream resolver for forwarding queries
-    pub fn new(bind_addr: &str, resolver: Option<String>) -> Result<Self, String> {
-        let socket = UdpSocket::bind(bind_addr)
-            .map_err(|e| format!("Failed to bind to {}: {}", bind_addr, e))?;
+    /// Create a new DNS server bound to each of the given addresses (both UDP and TCP)
+    /// answering from whatever `resolver` currently holds
+    /// UDP requests from all addresses are answered by one pool of workers sized by
+    /// `worker_config`
+    pub fn new(
+        bind_addrs: &[SocketAddr],
+        resolver: Arc<SharedResolver>,
+        worker_config: WorkerConfig,
+    ) -> Result<Self, String> {
+        if bind_addrs.is_empty() {
+            return Err("No address to listen on".to_string());
+        }
+
+        let listeners = bind_addrs
+            .iter()
+            .map(|&addr| {
+                let (udp_socket, tcp_listener) = bind_pair(addr)?;
+                Ok(Listener {
+                    udp_socket,
+                    tcp_listener,
+                })
+            })
+            .collect::<Result<_, String>>()?;
 
-        Ok(Self { socket, resolver })
+        Ok(Self {
+            listeners,
+            resolver,
+            worker_config,
+        })
     }
 
     /// Run the DNS server main loop
-    /// Listens for incoming requests and sends responses
+    /// Every listener gets a thread reading UDP datagrams and one accepting TCP
+    /// connections; datagrams are handed to the worker threads through a bounded queue
     pub fn run(&self) {
-        let mut buf = [0u8; 512];
+        let (sender, receiver) = mpsc::sync_channel(self.worker_config.queue_size);
+        let receiver = Mutex::new(receiver);
+
+        thread::scope(|scope| {
+            for listener in &self.listeners {
+                let sender = sender.clone();
+                scope.spawn(move || self.run_udp(&listener.udp_socket, sender));
+                scope.spawn(move || self.run_tcp(&listener.tcp_listener, scope));
+            }
+            drop(sender);
+
+            for _ in 0..self.worker_config.workers {
+                scope.spawn(|| self.run_udp_worker(&receiver));
+            }
+        });
+    }
+
+    /// Listen for UDP datagrams on `socket` and queue them for the workers
+    /// When the queue is full the request is answered with SERVFAIL right away,
+    /// so clients and logs see the overload instead of silent timeouts
+    fn run_udp<'a>(&self, socket: &'a UdpSocket, sender: SyncSender<UdpJob<'a>>) {
+        let mut buf = [0u8; SERVER_UDP_PAYLOAD as usize];
+        let mut shed: u64 = 0;
 
         loop {
-            match self.socket.recv_from(&mut buf) {
+            match socket.recv_from(&mut buf) {
                 Ok((size, source)) => {
-                    println!("Received {} bytes from {}", size, source);
+                    debug!("Received {} bytes from {}", size, source);
 
-                    match self.handle_request(&buf[..size]) {
-                        Ok(response) => {
-                            self.socket
-                                .send_to(&response, source)
-                                .expect("Failed to send response");
+                    match sender.try_send((buf[..size].to_vec(), source, socket)) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full((request, source, _))) => {
+                            shed += 1;
+                            warning!(
+                                "Request queue full, shedding request from {} ({} shed so far)",
+                                source,
+                                shed
+                            );
+                            if let Some(response) = overload_response(&request) {
+                                send_udp(socket, &response, source);
+                            }
                         }
-                        Err(e) => {
-                            eprintln!("Error handling request: {}", e);
+                        Err(TrySendError::Disconnected(_)) => {
+                            error!("All UDP workers have stopped");
+                            break;
                         }
                     }
                 }
                 Err(e) => {
-                    eprintln!("Error receiving data: {}", e);
+                    error!("Error receiving data: {}", e);
                     break;
                 }
             }
         }
     }
 
+    /// Answer queued UDP requests until the queue is closed
+    fn run_udp_worker(&self, receiver: &Mutex<Receiver<UdpJob<'_>>>) {
+        loop {
+            // The lock is only held while waiting for the next job, not while answering it
+            let job = receiver
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .recv();
+            let Ok((request, source, socket)) = job else {
+                return;
+            };
+
+            match self.handle_request(&request, Transport::Udp) {
+                Ok(response) => send_udp(socket, &response, source),
+                Err(e) => {
+                    error!("Error handling request: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Accept TCP connections on `listener`, serving each one on its own thread
+    fn run_tcp<'scope>(
+        &'scope self,
+        listener: &'scope TcpListener,
+        scope: &'scope Scope<'scope, '_>,
+    ) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    scope.spawn(move || self.handle_tcp_connection(stream));
+                }
+                Err(e) => {
+                    error!("Error accepting TCP connection: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Serve length-prefixed queries from a TCP connection until the client closes it
+    /// Clients may pipeline several queries; responses are sent back in the same order
+    fn handle_tcp_connection(&self, mut stream: TcpStream) {
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "unknown peer".to_string());
+
+        if let Err(e) = stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT)) {
+            error!("Failed to set TCP read timeout for {}: {}", peer, e);
+            return;
+        }
+
+        loop {
+            let request = match tcp::read_message(&mut stream) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Error reading TCP request from {}: {}", peer, e);
+                    break;
+                }
+            };
+
+            debug!("Received {} bytes over TCP from {}", request.len(), peer);
+
+            match self.handle_request(&request, Transport::Tcp) {
+                Ok(response) => {
+                    if let Err(e) = tcp::write_message(&mut stream, &response) {
+                        error!("Error sending TCP response to {}: {}", peer, e);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Error handling request: {}", e);
+                }
+            }
+        }
+    }
+
     /// Handle a DNS request: parse, resolve, and build response
-    fn handle_request(&self, buf: &[u8]) -> Result<Vec<u8>, String> {
-        // Parse the request
-        let (request_header, questions) = parse_request(buf)?;
-
-        // Get answers - either from upstream resolver or generate locally
-        let answers = if let Some(resolver_addr) = &self.resolver {
-            // Forward the request to the upstream resolver
-            forward_to_resolver(resolver_addr, request_header.id, &questions)?
-        } else {
-            // No resolver configured - create dummy response locally
-            create_response_answers(&questions)
+    fn handle_request(&self, buf: &[u8], transport: Transport) -> Result<Vec<u8>, DnsError> {
+        match triage_request(buf, transport)? {
+            Triage::Respond(response) => Ok(response),
+            Triage::Resolve(accepted) => {
+                let resolved = self.resolver.current().resolve(&accepted.request);
+                accepted.respond(resolved)
+            }
+        }
+    }
+}
+
+fn send_udp(socket: &UdpSocket, response: &[u8], destination: SocketAddr) {
+    if let Err(e) = socket.send_to(response, destination) {
+        error!("Error sending response to {}: {}", destination, e);
+    }
+}
+
+/// Outcome of the checks made on a request before anything is resolved
+pub(crate) enum Triage {
+    Respond(Vec<u8>), // Malformed or unsupported: the error response to send
+    Resolve(Accepted),
+}
+
+/// A request that passed the checks and now needs resolving
+pub(crate) struct Accepted {
+    pub request: DnsMessage,
+    edns: Option<EdnsOpt>, // The client's OPT record, echoed in the response
+    max_size: usize,       // Largest response the client can take
+}
+
+/// Parse a request and answer it right away if it is malformed or unsupported
+/// Failures are answered with FORMERR, BADVERS or NOTIMP; an error is only
+/// returned when not even the header could be read
+pub(crate) fn triage_request(buf: &[u8], transport: Transport) -> Result<Triage, DnsError> {
+    // Parse the request
+    let request = match DnsMessage::from_bytes(buf) {
+        Ok(request) => request,
+        Err(e) => {
+            // Without a readable header there is no ID to answer, so the error is returned
+            let header = DnsHeader::from_bytes(buf)?;
+            warning!("Malformed request: {}", e);
+            return build_error_response(&header, &[], None, e.rcode()).map(Triage::Respond);
+        }
+    };
+
+    let edns = match request.edns() {
+        Ok(edns) => edns,
+        Err(e) => {
+            warning!("Malformed EDNS in request: {}", e);
+            return build_error_response(&request.header, &request.questions, None, e.rcode())
+                .map(Triage::Respond);
+        }
+    };
+
+    // Reject EDNS versions we do not speak, answering with our own version
+    if let Some(opt) = edns.as_ref().filter(|opt| opt.version > EDNS_VERSION) {
+        return build_error_response(
+            &request.header,
+            &request.questions,
+            Some(opt),
+            RCODE_BADVERS,
+        )
+        .map(Triage::Respond);
+    }
+
+    // Only standard queries are supported
+    if DnsFlags::from_u16(request.header.flags).opcode != 0 {
+        return build_error_response(
+            &request.header,
+            &request.questions,
+            edns.as_ref(),
+            RCODE_NOTIMP,
+        )
+        .map(Triage::Respond);
+    }
+
+    let max_size = match transport {
+        Transport::Tcp => MAX_TCP_PAYLOAD,
+        Transport::Udp => edns
+            .as_ref()
+            .map_or(MAX_UDP_PAYLOAD, |opt| opt.max_response_size()),
+    };
+
+    Ok(Triage::Resolve(Accepted {
+        request,
+        edns,
+        max_size,
+    }))
+}
+
+impl Accepted {
+    /// Encode the response once the request has been resolved
+    /// UDP responses are truncated (TC set) to fit the client's advertised EDNS buffer;
+    /// a failure to resolve or encode is answered with SERVFAIL
+    pub(crate) fn respond(
+        self,
+        resolved: Result<DnsMessage, DnsError>,
+    ) -> Result<Vec<u8>, DnsError> {
+        let request = &self.request;
+
+        let mut response = match resolved {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Failed to resolve request: {}", e);
+                return build_error_response(
+                    &request.header,
+                    &request.questions,
+                    self.edns.as_ref(),
+                    RCODE_SERVFAIL,
+                );
+            }
         };
 
-        // Build response
-        let response_header = create_response_header(&request_header, answers.len() as u16);
-        let response = build_response(&response_header, &questions, &answers);
+        // Echo EDNS if the client used it
+        if let Some(opt) = &self.edns {
+            response
+                .additionals
+                .push(EdnsOpt::for_response(opt, 0).to_record());
+        }
+
+        build_response(&response, self.max_size).or_else(|e| {
+            error!("Failed to encode response: {}", e);
+            build_error_response(
+                &request.header,
+                &request.questions,
+                self.edns.as_ref(),
+                RCODE_SERVFAIL,
+            )
+        })
+    }
+}
+
+/// SERVFAIL for a request we have no capacity to answer
+/// The question is echoed when it can be parsed, so the client can match the reply
+fn overload_response(request: &[u8]) -> Option<Vec<u8>> {
+    match DnsMessage::from_bytes(request) {
+        Ok(message) => {
+            build_error_response(&message.header, &message.questions, None, RCODE_SERVFAIL).ok()
+        }
+        Err(_) => {
+            let header = DnsHeader::from_bytes(request).ok()?;
+            build_error_response(&header, &[], None, RCODE_SERVFAIL).ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_question_and_answer::DnsQuestion;
+
+    #[test]
+    fn test_overload_response_is_servfail() {
+        let mut request = DnsMessage::new(DnsHeader {
+            id: 0xBEEF,
+            flags: 0x0100,
+            question_count: 0,
+            answer_count: 0,
+            authority_count: 0,
+            additional_count: 0,
+        });
+        request.questions.push(DnsQuestion {
+            name: "example.com".to_string(),
+            qtype: 1,
+            qclass: 1,
+        });
+
+        let response = overload_response(&request.to_bytes().unwrap()).unwrap();
+        let response = DnsMessage::from_bytes(&response).unwrap();
+        assert_eq!(response.header.id, 0xBEEF);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(
+            DnsFlags::from_u16(response.header.flags).rcode as u16,
+            RCODE_SERVFAIL
+        );
 
-        Ok(response)
+        assert!(overload_response(&[0xBE]).is_none());
     }
 }
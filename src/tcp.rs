@@ -0,0 +1,119 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Read one length-prefixed DNS message from a TCP stream
+/// Format: LENGTH (2 bytes, big endian) + MESSAGE
+/// Returns Ok(None) when the peer closed the connection cleanly between messages
+pub fn read_message<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length_bytes = [0u8; 2];
+
+    match reader.read_exact(&mut length_bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let length = u16::from_be_bytes(length_bytes) as usize;
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message)?;
+
+    Ok(Some(message))
+}
+
+/// Write one DNS message to a TCP stream with its two-byte length prefix
+pub fn write_message<W: Write>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    // Send prefix and message in one write so they end up in the same segment
+    let framed = frame(message)?;
+    writer.write_all(&framed)?;
+    writer.flush()
+}
+
+/// Async version of `read_message` for tokio streams
+pub async fn read_message_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut length_bytes = [0u8; 2];
+
+    match reader.read_exact(&mut length_bytes).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let length = u16::from_be_bytes(length_bytes) as usize;
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message).await?;
+
+    Ok(Some(message))
+}
+
+/// Async version of `write_message` for tokio streams
+pub async fn write_message_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+) -> io::Result<()> {
+    let framed = frame(message)?;
+    writer.write_all(&framed).await?;
+    writer.flush().await
+}
+
+/// Prefix a message with its two-byte length
+fn frame(message: &[u8]) -> io::Result<Vec<u8>> {
+    let length = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "DNS message too large for TCP"))?;
+
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&length.to_be_bytes());
+    framed.extend_from_slice(message);
+    Ok(framed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_then_read_message() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &[1, 2, 3]).unwrap();
+        assert_eq!(buf, vec![0, 3, 1, 2, 3]);
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_message(&mut cursor).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(read_message(&mut cursor).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_pipelined_messages() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &[0xAA]).unwrap();
+        write_message(&mut buf, &[0xBB, 0xCC]).unwrap();
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_message(&mut cursor).unwrap(), Some(vec![0xAA]));
+        assert_eq!(read_message(&mut cursor).unwrap(), Some(vec![0xBB, 0xCC]));
+        assert_eq!(read_message(&mut cursor).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_truncated_message() {
+        let mut cursor = Cursor::new(vec![0, 5, 1, 2]);
+        assert!(read_message(&mut cursor).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_async_write_then_read_message() {
+        let mut buf = Vec::new();
+        write_message_async(&mut buf, &[1, 2, 3]).await.unwrap();
+        assert_eq!(buf, vec![0, 3, 1, 2, 3]);
+
+        let mut reader = &buf[..];
+        assert_eq!(
+            read_message_async(&mut reader).await.unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(read_message_async(&mut reader).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,261 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+
+use crate::error::DnsError;
+use crate::logging::{info, warning};
+
+/// Consecutive failed attempts after which an upstream is marked down
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// How long a down upstream is passed over before it is probed again
+const DOWN_PERIOD: Duration = Duration::from_secs(30);
+
+/// A new round-trip sample counts for 1/8 of the smoothed value (as TCP's SRTT)
+const RTT_SMOOTHING: u32 = 8;
+
+/// Order in which upstream resolvers are tried for each query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Always start with the first listed resolver, moving on when it fails
+    #[default]
+    Failover,
+    /// Start with the next resolver in the list on every query
+    RoundRobin,
+    /// Start with a randomly chosen resolver
+    Random,
+    /// Start with the resolver that has been answering fastest
+    LowestLatency,
+}
+
+/// What we have observed about one upstream so far
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    down_until: Option<Instant>, // Set once the failure threshold is reached
+    rtt: Option<Duration>,       // Smoothed round-trip time of answered queries
+}
+
+/// One upstream resolver and its health
+#[derive(Debug)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    fn new(addr: SocketAddr) -> Self {
+        Upstream {
+            addr,
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// Health is plain bookkeeping, so a panic elsewhere cannot leave it inconsistent
+    fn health(&self) -> MutexGuard<'_, Health> {
+        self.health.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether the upstream is up, or has been down long enough to be probed again
+    fn is_available(&self, now: Instant) -> bool {
+        self.health()
+            .down_until
+            .map_or(true, |down_until| now >= down_until)
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.health().rtt
+    }
+
+    /// Record an answered query and how long the answer took
+    pub fn record_success(&self, rtt: Duration) {
+        let mut health = self.health();
+
+        if health.down_until.take().is_some() {
+            info!("Resolver {} is answering again", self.addr);
+        }
+        health.consecutive_failures = 0;
+        health.rtt = Some(match health.rtt {
+            Some(smoothed) => (smoothed * (RTT_SMOOTHING - 1) + rtt) / RTT_SMOOTHING,
+            None => rtt,
+        });
+    }
+
+    /// Record an unanswered attempt, marking the upstream down once too many pile up
+    /// A failed probe sends it straight back down for another period
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now());
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut health = self.health();
+
+        health.consecutive_failures += 1;
+        if health.consecutive_failures < FAILURE_THRESHOLD {
+            return;
+        }
+
+        if health.down_until.is_none() {
+            warning!(
+                "Marking resolver {} down after {} failed attempts",
+                self.addr,
+                health.consecutive_failures
+            );
+        }
+        health.down_until = Some(now + DOWN_PERIOD);
+    }
+}
+
+/// The configured upstream resolvers and the strategy for choosing between them
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    next: AtomicUsize, // Round-robin position
+}
+
+impl UpstreamPool {
+    /// Resolve the given addresses (e.g. "8.8.8.8:53") into a pool
+    pub fn new(addrs: &[String], strategy: Strategy) -> Result<Self, DnsError> {
+        let mut upstreams = Vec::new();
+
+        for addr in addrs {
+            let resolved = addr
+                .to_socket_addrs()
+                .map_err(DnsError::io("Failed to resolve upstream address"))?
+                .next()
+                .ok_or_else(|| DnsError::Upstream(format!("No address for resolver {}", addr)))?;
+            upstreams.push(Upstream::new(resolved));
+        }
+
+        if upstreams.is_empty() {
+            return Err(DnsError::Upstream("No resolvers configured".to_string()));
+        }
+
+        Ok(UpstreamPool {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Upstreams in the order they should be tried for the next query
+    pub fn candidates(&self) -> Vec<&Upstream> {
+        self.candidates_at(Instant::now())
+    }
+
+    fn candidates_at(&self, now: Instant) -> Vec<&Upstream> {
+        let mut order: Vec<&Upstream> = self.upstreams.iter().collect();
+
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % order.len();
+                order.rotate_left(start);
+            }
+            Strategy::Random => order.shuffle(&mut rand::thread_rng()),
+            // Upstreams that have not answered yet sort first, so they get measured
+            Strategy::LowestLatency => order.sort_by_key(|upstream| upstream.rtt()),
+        }
+
+        // Down upstreams are only a last resort; the sort is stable, so the order chosen
+        // above is kept within each group
+        order.sort_by_key(|upstream| !upstream.is_available(now));
+        order
+    }
+}
+
+impl std::fmt::Display for UpstreamPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, upstream) in self.upstreams.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", upstream.addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: Strategy) -> UpstreamPool {
+        let addrs = ["127.0.0.1:5301", "127.0.0.1:5302", "127.0.0.1:5303"];
+        let addrs: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+        UpstreamPool::new(&addrs, strategy).unwrap()
+    }
+
+    fn ports(candidates: &[&Upstream]) -> Vec<u16> {
+        candidates
+            .iter()
+            .map(|upstream| upstream.addr.port())
+            .collect()
+    }
+
+    #[test]
+    fn test_failover_keeps_list_order() {
+        let pool = pool(Strategy::Failover);
+        assert_eq!(ports(&pool.candidates()), vec![5301, 5302, 5303]);
+        assert_eq!(ports(&pool.candidates()), vec![5301, 5302, 5303]);
+    }
+
+    #[test]
+    fn test_round_robin_rotates() {
+        let pool = pool(Strategy::RoundRobin);
+        assert_eq!(ports(&pool.candidates()), vec![5301, 5302, 5303]);
+        assert_eq!(ports(&pool.candidates()), vec![5302, 5303, 5301]);
+        assert_eq!(ports(&pool.candidates()), vec![5303, 5301, 5302]);
+        assert_eq!(ports(&pool.candidates()), vec![5301, 5302, 5303]);
+    }
+
+    #[test]
+    fn test_random_tries_every_upstream() {
+        let pool = pool(Strategy::Random);
+        let mut order = ports(&pool.candidates());
+        order.sort();
+        assert_eq!(order, vec![5301, 5302, 5303]);
+    }
+
+    #[test]
+    fn test_lowest_latency_prefers_fastest() {
+        let pool = pool(Strategy::LowestLatency);
+        pool.upstreams[0].record_success(Duration::from_millis(80));
+        pool.upstreams[1].record_success(Duration::from_millis(10));
+        pool.upstreams[2].record_success(Duration::from_millis(40));
+        assert_eq!(ports(&pool.candidates()), vec![5302, 5303, 5301]);
+    }
+
+    #[test]
+    fn test_down_upstream_is_tried_last_until_probed() {
+        let pool = pool(Strategy::Failover);
+        let now = Instant::now();
+
+        for _ in 0..FAILURE_THRESHOLD - 1 {
+            pool.upstreams[0].record_failure_at(now);
+        }
+        assert_eq!(ports(&pool.candidates_at(now)), vec![5301, 5302, 5303]);
+
+        pool.upstreams[0].record_failure_at(now);
+        assert_eq!(ports(&pool.candidates_at(now)), vec![5302, 5303, 5301]);
+
+        // Once the down period is over it is probed in its usual place again
+        let later = now + DOWN_PERIOD;
+        assert_eq!(ports(&pool.candidates_at(later)), vec![5301, 5302, 5303]);
+
+        // A failed probe sends it straight back down
+        pool.upstreams[0].record_failure_at(later);
+        assert_eq!(ports(&pool.candidates_at(later)), vec![5302, 5303, 5301]);
+
+        // An answer brings it back for good
+        pool.upstreams[0].record_success(Duration::from_millis(5));
+        assert_eq!(ports(&pool.candidates_at(later)), vec![5301, 5302, 5303]);
+    }
+}
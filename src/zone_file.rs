@@ -0,0 +1,481 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::dns_question_and_answer::{DnsAnswer, RecordClass, RecordType};
+use crate::error::ConfigError;
+use crate::rdata::{qualify_name, RData};
+
+/// How deep `$INCLUDE`s may nest, which also stops a file from including itself
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// One field of an entry; quoted fields are never taken for a TTL, class or type
+#[derive(Debug, PartialEq)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// One record or directive, which parentheses may spread over several lines
+#[derive(Debug)]
+struct Entry {
+    line: usize,         // Where the entry starts, for error messages
+    owner_omitted: bool, // Started with a blank: the previous owner carries over
+    tokens: Vec<Token>,
+}
+
+/// Split master file text into entries (RFC 1035 section 5.1)
+/// Handles comments, quoted strings, backslash escapes and parentheses
+fn entries(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut depth = 0; // Open parentheses
+    let mut entry: Option<Entry> = None;
+    let mut at_line_start = true;
+
+    while let Some(c) = chars.next() {
+        let starts_line = std::mem::replace(&mut at_line_start, false);
+        let current = entry.get_or_insert_with(|| Entry {
+            line,
+            owner_omitted: starts_line && (c == ' ' || c == '\t'),
+            tokens: Vec::new(),
+        });
+
+        match c {
+            '\n' => {
+                line += 1;
+                at_line_start = depth == 0;
+                if depth == 0 {
+                    if let Some(done) = entry.take().filter(|done| !done.tokens.is_empty()) {
+                        entries.push(done);
+                    }
+                }
+            }
+            ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err((line, "Unbalanced ')'".to_string()));
+                }
+                depth -= 1;
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.push(escaped(&mut chars, line)?),
+                        Some('\n') | None => {
+                            return Err((line, "Unterminated quoted string".to_string()))
+                        }
+                        Some(c) => text.push(c),
+                    }
+                }
+                current.tokens.push(Token { text, quoted: true });
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut text = String::new();
+                let mut next = Some(c);
+                while let Some(c) = next {
+                    if c == '\\' {
+                        text.push(escaped(&mut chars, line)?);
+                    } else {
+                        text.push(c);
+                    }
+                    next = chars.next_if(|&c| !c.is_whitespace() && !"();\"".contains(c));
+                }
+                current.tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+
+    if depth > 0 {
+        return Err((line, "Unclosed '('".to_string()));
+    }
+    if let Some(done) = entry.filter(|done| !done.tokens.is_empty()) {
+        entries.push(done);
+    }
+    Ok(entries)
+}
+
+/// The character after a backslash: either itself or `\DDD`, a decimal ASCII code
+fn escaped(
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    line: usize,
+) -> Result<char, (usize, String)> {
+    let first = chars
+        .next()
+        .ok_or((line, "Dangling backslash".to_string()))?;
+    if !first.is_ascii_digit() {
+        return Ok(first);
+    }
+
+    let mut code = first.to_digit(10).unwrap_or(0);
+    for _ in 0..2 {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .ok_or((line, "Escape needs three digits (\\DDD)".to_string()))?;
+        code = code * 10 + digit;
+    }
+    // Text is kept as a string, so only ASCII can be written this way
+    u8::try_from(code)
+        .ok()
+        .filter(u8::is_ascii)
+        .map(char::from)
+        .ok_or((line, format!("Escape \\{} is not ASCII", code)))
+}
+
+/// Parse a TTL: plain seconds, or BIND-style units such as `1h30m` or `2d`
+pub fn parse_ttl(text: &str) -> Option<u32> {
+    if !text.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Ok(seconds) = text.parse() {
+        return Some(seconds);
+    }
+
+    let mut total: u32 = 0;
+    let mut number: Option<u32> = None;
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = Some(number.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(number.take()?.checked_mul(unit)?)?;
+    }
+
+    match number {
+        Some(_) => None, // Trailing number without a unit
+        None => Some(total),
+    }
+}
+
+/// Parsing state for one file
+/// An included file starts with the origin given to `$INCLUDE` and the `$TTL` in effect,
+/// and what it changes does not carry back into the including file
+struct FileState {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_ttl: Option<u32>,
+}
+
+impl FileState {
+    fn new(origin: &str, default_ttl: Option<u32>) -> Self {
+        FileState {
+            origin: origin.to_string(),
+            default_ttl,
+            last_owner: None,
+            last_ttl: None,
+        }
+    }
+}
+
+/// Reads zone files into records
+struct Loader {
+    records: Vec<DnsAnswer>,
+}
+
+impl Loader {
+    fn load(&mut self, path: &Path, state: FileState, depth: usize) -> Result<(), ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+        self.parse(&text, path, state, depth)
+    }
+
+    fn parse(
+        &mut self,
+        text: &str,
+        path: &Path,
+        mut state: FileState,
+        depth: usize,
+    ) -> Result<(), ConfigError> {
+        let at = |line: usize, message: String| ConfigError::Zone {
+            path: path.display().to_string(),
+            line,
+            message,
+        };
+
+        for entry in entries(text).map_err(|(line, message)| at(line, message))? {
+            let line = entry.line;
+            let first = &entry.tokens[0];
+
+            if !entry.owner_omitted && !first.quoted && first.text.starts_with('$') {
+                let arguments: Vec<&str> = entry.tokens[1..]
+                    .iter()
+                    .map(|token| token.text.as_str())
+                    .collect();
+                match (
+                    first.text.to_ascii_uppercase().as_str(),
+                    arguments.as_slice(),
+                ) {
+                    ("$ORIGIN", [name]) => {
+                        state.origin = qualify_name(name, &state.origin)
+                            .map_err(|e| at(line, e.to_string()))?;
+                    }
+                    ("$TTL", [ttl]) => {
+                        state.default_ttl = Some(
+                            parse_ttl(ttl)
+                                .ok_or_else(|| at(line, format!("Invalid TTL {}", ttl)))?,
+                        );
+                    }
+                    ("$INCLUDE", [file, rest @ ..]) if rest.len() <= 1 => {
+                        if depth >= MAX_INCLUDE_DEPTH {
+                            return Err(at(line, "$INCLUDE nested too deeply".to_string()));
+                        }
+                        let include_origin = match rest.first() {
+                            Some(name) => qualify_name(name, &state.origin)
+                                .map_err(|e| at(line, e.to_string()))?,
+                            None => state.origin.clone(),
+                        };
+                        let mut include = PathBuf::from(file);
+                        if include.is_relative() {
+                            if let Some(directory) = path.parent() {
+                                include = directory.join(include);
+                            }
+                        }
+                        let included = FileState::new(&include_origin, state.default_ttl);
+                        self.load(&include, included, depth + 1)?;
+                    }
+                    (directive, _) => {
+                        return Err(at(line, format!("Invalid directive {}", directive)));
+                    }
+                }
+                continue;
+            }
+
+            let record = self
+                .record(&entry, &mut state)
+                .map_err(|message| at(line, message))?;
+            self.records.push(record);
+        }
+
+        Ok(())
+    }
+
+    /// Parse `[owner] [TTL] [class] type RDATA`, where TTL and class may come in
+    /// either order
+    fn record(&self, entry: &Entry, state: &mut FileState) -> Result<DnsAnswer, String> {
+        let mut tokens = entry.tokens.iter().peekable();
+
+        let owner = if entry.owner_omitted {
+            state
+                .last_owner
+                .clone()
+                .ok_or("No owner name, and no previous record to take it from")?
+        } else {
+            let name = tokens
+                .next()
+                .map(|token| token.text.as_str())
+                .unwrap_or("@");
+            qualify_name(name, &state.origin).map_err(|e| e.to_string())?
+        };
+
+        let mut ttl = None;
+        let mut class = None;
+        let rtype = loop {
+            let token = tokens.next().ok_or("Missing record type")?;
+            if token.quoted {
+                return Err(format!("Expected a record type, got \"{}\"", token.text));
+            }
+            if ttl.is_none() {
+                if let Some(value) = parse_ttl(&token.text) {
+                    ttl = Some(value);
+                    continue;
+                }
+            }
+            if class.is_none() && token.text.eq_ignore_ascii_case("IN") {
+                class = Some(RecordClass::IN);
+                continue;
+            }
+            if ["CS", "CH", "HS"].contains(&token.text.to_ascii_uppercase().as_str()) {
+                return Err(format!("Unsupported class {}", token.text));
+            }
+            break RecordType::from_name(&token.text)
+                .ok_or_else(|| format!("Unsupported record type {}", token.text))?;
+        };
+
+        let mut fields: Vec<String> = tokens.map(|token| token.text.clone()).collect();
+        if rtype == RecordType::SOA && fields.len() == 7 {
+            // Timers may use units too; the serial is a plain number
+            for field in &mut fields[3..] {
+                if let Some(seconds) = parse_ttl(field) {
+                    *field = seconds.to_string();
+                }
+            }
+        }
+        let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+        let rdata = RData::from_presentation(rtype, &fields, &state.origin)
+            .and_then(|data| data.to_bytes())
+            .map_err(|e| e.to_string())?;
+
+        let ttl = ttl
+            .or(state.default_ttl)
+            .or(state.last_ttl)
+            .ok_or("No TTL, and no $TTL or previous record to take it from")?;
+
+        state.last_owner = Some(owner.clone());
+        state.last_ttl = Some(ttl);
+
+        Ok(DnsAnswer::new(
+            owner,
+            rtype.to_u16(),
+            RecordClass::IN.to_u16(),
+            ttl,
+            rdata,
+        ))
+    }
+}
+
+/// Read a zone file, and the files it includes, into records
+/// Relative names are completed with `origin` until `$ORIGIN` says otherwise
+pub fn load_zone_file(path: &Path, origin: &str) -> Result<Vec<DnsAnswer>, ConfigError> {
+    let mut loader = Loader {
+        records: Vec::new(),
+    };
+    loader.load(path, FileState::new(origin, None), 0)?;
+    Ok(loader.records)
+}
+
+#[cfg(test)]
+pub fn parse_zone(text: &str, origin: &str) -> Result<Vec<DnsAnswer>, ConfigError> {
+    let mut loader = Loader {
+        records: Vec::new(),
+    };
+    loader.parse(
+        text,
+        Path::new("test.zone"),
+        FileState::new(origin, None),
+        0,
+    )?;
+    Ok(loader.records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = r#"
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            2h         ; refresh
+            15m        ; retry
+            2w         ; expire
+            300 )      ; minimum
+    IN  NS  ns1
+    IN  MX  10 mail.example.com.
+ns1     A   192.0.2.1
+mail 60 IN A 192.0.2.2
+        IN AAAA 2001:db8::2
+txt     TXT "hello; world" "with \"quotes\""
+$ORIGIN sub
+host    A   192.0.2.3
+"#;
+
+    fn data(records: &[DnsAnswer], index: usize) -> RData {
+        records[index].data().unwrap()
+    }
+
+    #[test]
+    fn test_parse_zone() {
+        let records = parse_zone(ZONE, "example.com").unwrap();
+        let owners: Vec<&str> = records.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            owners,
+            vec![
+                "example.com",
+                "example.com",
+                "example.com",
+                "ns1.example.com",
+                "mail.example.com",
+                "mail.example.com",
+                "txt.example.com",
+                "host.sub.example.com",
+            ]
+        );
+
+        assert_eq!(
+            data(&records, 0),
+            RData::SOA {
+                mname: "ns1.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 900,
+                expire: 1209600,
+                minimum: 300,
+            }
+        );
+        assert_eq!(records[0].ttl, 3600);
+        assert_eq!(records[4].ttl, 60);
+        assert_eq!(records[5].rtype, RecordType::AAAA.to_u16());
+        assert_eq!(
+            data(&records, 6),
+            RData::TXT(vec![b"hello; world".to_vec(), b"with \"quotes\"".to_vec()])
+        );
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("300"), Some(300));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1W"), Some(604800));
+        assert_eq!(parse_ttl("1h30"), None);
+        assert_eq!(parse_ttl("h"), None);
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        let error = parse_zone(
+            "$TTL 60\n@ SOA ns hm 1 2 3 4 5\nwww A 192.0.2.300\n",
+            "example.com",
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, ConfigError::Zone { line: 3, .. }),
+            "{}",
+            error
+        );
+
+        assert!(parse_zone("www A 192.0.2.1\n", "example.com").is_err()); // No TTL
+        assert!(parse_zone("www 60 A ( 192.0.2.1\n", "example.com").is_err());
+        assert!(parse_zone("www 60 CH A 192.0.2.1\n", "example.com").is_err());
+        assert!(parse_zone("$GENERATE 1-2 x A 1.2.3.4\n", "example.com").is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let directory = std::env::temp_dir().join(format!("dns-zone-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("hosts.inc"), "www A 192.0.2.10\n$TTL 30\n").unwrap();
+        fs::write(
+            directory.join("main.zone"),
+            "$TTL 60\n$INCLUDE hosts.inc lan.example.com.\nwww A 192.0.2.20\n",
+        )
+        .unwrap();
+
+        let records = load_zone_file(&directory.join("main.zone"), "example.com").unwrap();
+        let owners: Vec<&str> = records.iter().map(|r| r.name.as_str()).collect();
+        // The $TTL is inherited, but the included origin and $TTL do not leak back
+        assert_eq!(owners, vec!["www.lan.example.com", "www.example.com"]);
+        assert_eq!((records[0].ttl, records[1].ttl), (60, 60));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}